@@ -0,0 +1 @@
+ALTER TABLE users_accounts DROP COLUMN role;
@@ -0,0 +1,4 @@
+ALTER TABLE users_accounts ADD COLUMN role INT NOT NULL DEFAULT 0;
+
+-- Accounts have had a single member so far, which makes them the owner
+UPDATE users_accounts SET role = 2;
@@ -0,0 +1,15 @@
+CREATE TABLE revocations(
+  id UUID NOT NULL PRIMARY KEY,
+  kind INT NOT NULL,
+  subject TEXT,
+  jti TEXT,
+  issued_before TIMESTAMPTZ,
+  reason TEXT,
+  expires TIMESTAMPTZ,
+  created TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  deleted TIMESTAMPTZ,
+  CHECK (jti IS NOT NULL OR subject IS NOT NULL)
+);
+CREATE INDEX revocations_subject_idx ON revocations(subject);
+CREATE INDEX revocations_jti_idx ON revocations(jti);
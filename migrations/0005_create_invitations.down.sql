@@ -0,0 +1,3 @@
+DROP TABLE invitations;
+DROP INDEX users_accounts_account_id_idx;
+CREATE UNIQUE INDEX users_accounts_account_id_idx ON users_accounts(account_id);
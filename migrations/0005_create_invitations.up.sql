@@ -0,0 +1,18 @@
+-- Accounts can have any number of members
+DROP INDEX users_accounts_account_id_idx;
+CREATE INDEX users_accounts_account_id_idx ON users_accounts(account_id);
+
+CREATE TABLE invitations(
+  id UUID NOT NULL PRIMARY KEY,
+  account_id UUID NOT NULL REFERENCES accounts(id),
+  email TEXT NOT NULL,
+  role INT NOT NULL DEFAULT 0,
+  invited_by UUID REFERENCES users(id),
+  accepted_by UUID REFERENCES users(id),
+  expires TIMESTAMPTZ NOT NULL,
+  accepted TIMESTAMPTZ,
+  revoked TIMESTAMPTZ,
+  created TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX invitations_account_id_idx ON invitations(account_id);
@@ -0,0 +1,10 @@
+-- Audit record of users removed for good, without any of their personal data
+CREATE TABLE tombstones(
+  id UUID NOT NULL PRIMARY KEY,
+  user_id UUID NOT NULL,
+  kind INT NOT NULL,
+  requested_by TEXT,
+  created TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX tombstones_user_id_idx ON tombstones(user_id);
+CREATE INDEX users_deleted_idx ON users(deleted) WHERE deleted IS NOT NULL;
@@ -0,0 +1,11 @@
+CREATE TABLE exports(
+  id UUID NOT NULL PRIMARY KEY,
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  requested_by TEXT,
+  status INT NOT NULL DEFAULT 0,
+  data JSONB,
+  expires TIMESTAMPTZ,
+  created TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX exports_user_id_idx ON exports(user_id);
@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN email TEXT;
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN avatar_url TEXT;
+CREATE INDEX users_email_idx ON users(email);
@@ -0,0 +1,5 @@
+DROP INDEX users_email_trgm_idx;
+DROP INDEX users_name_trgm_idx;
+DROP INDEX users_profile_search_idx;
+DROP INDEX accounts_name_trgm_idx;
+DROP INDEX accounts_name_search_idx;
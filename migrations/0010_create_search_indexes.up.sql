@@ -0,0 +1,6 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE INDEX accounts_name_search_idx ON accounts USING GIN (to_tsvector('simple', name));
+CREATE INDEX accounts_name_trgm_idx ON accounts USING GIN (name gin_trgm_ops);
+CREATE INDEX users_profile_search_idx ON users USING GIN (to_tsvector('simple', coalesce(name, '') || ' ' || coalesce(email, '')));
+CREATE INDEX users_name_trgm_idx ON users USING GIN (name gin_trgm_ops);
+CREATE INDEX users_email_trgm_idx ON users USING GIN (email gin_trgm_ops);
@@ -0,0 +1,7 @@
+-- Rate limit state is cheap to lose, skipping the WAL keeps writes fast
+CREATE UNLOGGED TABLE rate_limits(
+  key TEXT NOT NULL PRIMARY KEY,
+  tokens DOUBLE PRECISION NOT NULL,
+  granted INT NOT NULL,
+  updated TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,2 @@
+DROP INDEX rate_limits_refilled_idx;
+ALTER TABLE rate_limits DROP COLUMN refilled;
@@ -0,0 +1,8 @@
+-- Buckets are kept per route group and caller, when they'll be full again
+-- depends on the group's limit
+ALTER TABLE rate_limits ADD COLUMN refilled TIMESTAMPTZ;
+CREATE INDEX rate_limits_refilled_idx ON rate_limits(refilled);
+
+-- Existing buckets don't record their limit, they're left to the next purge
+-- and recreated full when they're next used
+UPDATE rate_limits SET refilled = updated;
@@ -0,0 +1,2 @@
+-- Whether the name was set through the API, provider names don't overwrite it
+ALTER TABLE users ADD COLUMN name_overridden BOOLEAN NOT NULL DEFAULT FALSE;
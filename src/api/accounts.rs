@@ -1,75 +1,313 @@
 use std::sync::Arc;
 
 use axum::{
-    extract::{rejection::PathRejection, Path, State},
+    extract::{rejection::JsonRejection, State},
     http::StatusCode,
     response::IntoResponse,
-    routing::{delete, get, post},
-    Json, Router,
+    routing::{delete, get, patch, post},
+    Json,
 };
 use chrono::{DateTime, Utc};
 use sea_orm::{entity::*, query::*};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::entity::{accounts, prelude::*, users};
+use crate::entity::{
+    accounts::{self, AccountStatus},
+    prelude::*,
+    soft_delete::SoftDeleteFilter,
+    subscriptions, users,
+    users_accounts::{self, MembershipRole},
+};
 use crate::error::Error;
 
-use super::{auth::AuthUser, pagination::Pagination, ApiContext};
+use super::{
+    auth::AuthUser,
+    expand::{Expand, Fields},
+    filters::{self, Filters, ParseValue},
+    memberships::{AccountAccess, ACCOUNT_ADMIN},
+    pagination::{Page, Pagination},
+    permissions::{Permission, Routes},
+    scope::IncludeDeleted,
+    users::USER_SORT_KEYS,
+    validation::{non_null, validate_name},
+    ApiContext,
+};
+
+/// Fields accounts can be sorted by besides `id`
+pub const ACCOUNT_SORT_KEYS: &[(&str, accounts::Column)] = &[
+    ("name", accounts::Column::Name),
+    ("status", accounts::Column::Status),
+    ("created", accounts::Column::Created),
+    ("updated", accounts::Column::Updated),
+];
+
+/// Fields accounts can be filtered by
+pub const ACCOUNT_FILTERS: &[(&str, accounts::Column, ParseValue)] = &[
+    ("name", accounts::Column::Name, filters::text),
+    ("status", accounts::Column::Status, parse_status),
+    ("created", accounts::Column::Created, filters::timestamp),
+    ("updated", accounts::Column::Updated, filters::timestamp),
+];
+
+/// Relations that can be expanded on accounts, with the permission needed
+pub const ACCOUNT_EXPANSIONS: &[(&str, &str)] = &[
+    ("users", "list:user:account"),
+    ("subscription", "retrieve:subscription"),
+];
+
+/// An account status by name, e.g. `active`
+fn parse_status(value: &str) -> Option<Value> {
+    AccountStatus::iter()
+        .find(|status| format!("{status:?}").eq_ignore_ascii_case(value))
+        .map(|status| status.into_value().into())
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateAccount {
-    pub name: Option<String>,
+    pub name: String,
 }
 
+/// An account along with the caller's membership in it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountWithMembership {
+    #[serde(flatten)]
+    pub account: accounts::Model,
+    pub membership: users_accounts::Model,
+}
+
+/// Partial account update. Absent fields are left untouched, and explicit
+/// `null`s are rejected since neither column is nullable.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpdateAccount {
-    pub provider_id: Option<String>,
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    pub name: Option<Option<String>>,
+    /// Only global account admins can change an account's status
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    pub status: Option<Option<AccountStatus>>,
 }
 
-pub fn routes() -> Router<Arc<ApiContext>> {
-    Router::new()
-        .route("/v1/accounts", get(list_accounts_handler))
-        .route("/v1/accounts/:id", get(get_account_by_id_handler))
-        .route("/v1/accounts", post(create_account_handler))
-        .route("/v1/accounts/:id", delete(delete_account_handler))
-        .route("/v1/accounts/:id/users", get(list_account_users_handler))
+pub fn routes() -> Routes {
+    Routes::new()
+        .route_with_permission(
+            "/v1/accounts",
+            get(list_accounts_handler),
+            Permission::AllOf(&["list:account"]),
+        )
+        .route_with_permission(
+            "/v1/accounts/:id",
+            get(get_account_by_id_handler),
+            Permission::AllOf(&["retrieve:account"]),
+        )
+        .route_with_permission(
+            "/v1/accounts",
+            post(create_account_handler),
+            Permission::AllOf(&["create:account"]),
+        )
+        .route_with_permission(
+            "/v1/accounts/:id",
+            patch(update_account_handler),
+            Permission::AllOf(&["update:account"]),
+        )
+        .route_with_permission(
+            "/v1/accounts/:id",
+            delete(delete_account_handler),
+            Permission::AllOf(&["delete:account"]),
+        )
+        .route_with_permission(
+            "/v1/accounts/:id/restore",
+            post(restore_account_handler),
+            Permission::AllOf(&["restore:account"]),
+        )
+        .route_with_permission(
+            "/v1/accounts/:id/users",
+            get(list_account_users_handler),
+            Permission::AllOf(&["list:user:account"]),
+        )
 }
 
+/// List accounts, limited to those `member` belongs to when given
 pub async fn list_accounts(
     ctx: &Arc<ApiContext>,
     page: &Pagination,
-) -> Result<Vec<accounts::Model>, Error> {
-    let accounts = Accounts::find()
-        .filter(accounts::Column::Id.gte(page.after))
-        .order_by_asc(accounts::Column::Id)
-        .limit(page.limit)
-        .all(&ctx.db)
-        .await?;
-    Ok(accounts)
+    filters: &Filters,
+    member: Option<Uuid>,
+    include_deleted: bool,
+) -> Result<Page<accounts::Model>, Error> {
+    let sort = page.sort(accounts::Column::Id, ACCOUNT_SORT_KEYS)?;
+    let mut query = Accounts::find()
+        .scoped::<Accounts>(include_deleted)
+        .filter(filters.condition(ACCOUNT_FILTERS)?);
+    if let Some(user_id) = member {
+        query = query
+            .join(
+                JoinType::InnerJoin,
+                users_accounts::Relation::Accounts.def().rev(),
+            )
+            .filter(users_accounts::Column::UserId.eq(user_id))
+            .active::<UsersAccounts>();
+    }
+    sort.fetch(ctx, query).await
 }
 
-pub async fn get_account_by_id(ctx: &Arc<ApiContext>, id: Uuid) -> Result<accounts::Model, Error> {
+pub async fn get_account_by_id(
+    ctx: &Arc<ApiContext>,
+    id: Uuid,
+    include_deleted: bool,
+) -> Result<accounts::Model, Error> {
     Accounts::find_by_id(id)
+        .scoped::<Accounts>(include_deleted)
         .one(&ctx.db)
         .await?
         .ok_or(Error::NotFound)
 }
 
-pub async fn create_account(ctx: &Arc<ApiContext>) -> Result<accounts::Model, Error> {
+/// An account with its requested relations embedded
+#[derive(Debug, Serialize)]
+pub struct ExpandedAccount {
+    #[serde(flatten)]
+    pub account: accounts::Model,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub users: Option<Vec<users::Model>>,
+    /// The account's current subscription, `null` when it has none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription: Option<Option<subscriptions::Model>>,
+}
+
+/// Embed the requested relations in accounts, loading each relation for
+/// every account at once
+pub async fn expand_accounts(
+    ctx: &ApiContext,
+    accounts: Vec<accounts::Model>,
+    expand: &Expand,
+) -> Result<Vec<ExpandedAccount>, Error> {
+    let mut users: Vec<Option<Vec<users::Model>>> = vec![None; accounts.len()];
+    if expand.contains("users") {
+        let memberships = accounts
+            .load_many(
+                UsersAccounts::find()
+                    .active::<UsersAccounts>()
+                    .order_by_asc(users_accounts::Column::UserId),
+                &ctx.db,
+            )
+            .await?;
+        let counts: Vec<usize> = memberships.iter().map(Vec::len).collect();
+        let memberships: Vec<_> = memberships.into_iter().flatten().collect();
+        let mut loaded = memberships
+            .load_one(Users::find().active::<Users>(), &ctx.db)
+            .await?
+            .into_iter();
+        for (users, count) in users.iter_mut().zip(counts) {
+            *users = Some(loaded.by_ref().take(count).flatten().collect());
+        }
+    }
+    let mut subscription: Vec<Option<Option<subscriptions::Model>>> = vec![None; accounts.len()];
+    if expand.contains("subscription") {
+        let loaded = accounts
+            .load_many(
+                Subscriptions::find()
+                    .active::<Subscriptions>()
+                    .order_by_desc(subscriptions::Column::Created),
+                &ctx.db,
+            )
+            .await?;
+        for (subscription, loaded) in subscription.iter_mut().zip(loaded) {
+            *subscription = Some(loaded.into_iter().next());
+        }
+    }
+    let expanded = accounts
+        .into_iter()
+        .zip(users)
+        .zip(subscription)
+        .map(|((account, users), subscription)| ExpandedAccount {
+            account,
+            users,
+            subscription,
+        })
+        .collect();
+    Ok(expanded)
+}
+
+/// Create an account owned by `owner`, the account and membership are
+/// inserted in a single transaction
+pub async fn create_account(
+    ctx: &Arc<ApiContext>,
+    owner: &users::Model,
+    account: CreateAccount,
+) -> Result<AccountWithMembership, Error> {
+    let name = validate_name(&account.name)?;
+    let txn = ctx.db.begin().await?;
     let account = accounts::ActiveModel {
         id: Set(Uuid::now_v7()),
+        name: Set(name),
         ..Default::default()
     };
-    let account = account.insert(&ctx.db).await?;
+    let account = account.insert(&txn).await?;
+    let membership = users_accounts::ActiveModel {
+        user_id: Set(owner.id),
+        account_id: Set(account.id),
+        role: Set(MembershipRole::Owner),
+        ..Default::default()
+    };
+    let membership = membership.insert(&txn).await?;
+    txn.commit().await?;
+    Ok(AccountWithMembership {
+        account,
+        membership,
+    })
+}
+
+pub async fn update_account(
+    ctx: &Arc<ApiContext>,
+    id: Uuid,
+    update: UpdateAccount,
+) -> Result<accounts::Model, Error> {
+    let name = non_null("name", update.name)?
+        .map(|name| validate_name(&name))
+        .transpose()?;
+    let status = non_null("status", update.status)?;
+    let account = Accounts::find_by_id(id).one(&ctx.db).await?;
+    let account = account.ok_or(Error::NotFound)?;
+    if account.deleted.is_some() {
+        return Err(Error::Conflict(String::from("account is deleted")));
+    }
+    let mut account: accounts::ActiveModel = account.into();
+    if let Some(name) = name {
+        account.name = Set(name);
+    }
+    if let Some(status) = status {
+        account.status = Set(status);
+    }
+    account.updated = Set(DateTime::from(Utc::now()));
+    let account = account.update(&ctx.db).await?;
     Ok(account)
 }
 
 pub async fn delete_account(ctx: &Arc<ApiContext>, id: Uuid) -> Result<accounts::Model, Error> {
-    let account = Accounts::find_by_id(id).one(&ctx.db).await?;
+    let account = Accounts::find_by_id(id)
+        .active::<Accounts>()
+        .one(&ctx.db)
+        .await?;
+    let account = account.ok_or(Error::NotFound)?;
+    let mut account: accounts::ActiveModel = account.into();
+    let now = DateTime::from(Utc::now());
+    account.deleted = Set(Some(now));
+    account.updated = Set(now);
+    let account = account.update(&ctx.db).await?;
+    Ok(account)
+}
+
+/// Undo a soft delete, memberships are kept while an account is deleted so
+/// its members regain access
+pub async fn restore_account(ctx: &Arc<ApiContext>, id: Uuid) -> Result<accounts::Model, Error> {
+    let account = Accounts::find_by_id(id)
+        .deleted::<Accounts>()
+        .one(&ctx.db)
+        .await?;
     let account = account.ok_or(Error::NotFound)?;
     let mut account: accounts::ActiveModel = account.into();
-    account.deleted = Set(Some(DateTime::from(Utc::now())));
+    account.deleted = Set(None);
+    account.updated = Set(DateTime::from(Utc::now()));
     let account = account.update(&ctx.db).await?;
     Ok(account)
 }
@@ -78,68 +316,115 @@ pub async fn list_account_users(
     ctx: &Arc<ApiContext>,
     id: Uuid,
     page: &Pagination,
-) -> Result<Vec<users::Model>, Error> {
-    let result = Accounts::find()
-        .find_with_related(Users)
-        .filter(accounts::Column::Id.eq(id))
-        .filter(users::Column::Id.gte(page.after))
-        .order_by_asc(users::Column::Id)
-        .limit(page.limit)
-        .all(&ctx.db)
-        .await?;
-    let (_, users) = result.first().ok_or(Error::NotFound)?.to_owned();
-    Ok(users)
+    include_deleted: bool,
+) -> Result<Page<users::Model>, Error> {
+    let sort = page.sort(users::Column::Id, USER_SORT_KEYS)?;
+    Accounts::find_by_id(id)
+        .scoped::<Accounts>(include_deleted)
+        .one(&ctx.db)
+        .await?
+        .ok_or(Error::NotFound)?;
+    let query = Users::find()
+        .join(
+            JoinType::InnerJoin,
+            users_accounts::Relation::Users.def().rev(),
+        )
+        .filter(users_accounts::Column::AccountId.eq(id))
+        .scoped::<Users>(include_deleted)
+        .scoped::<UsersAccounts>(include_deleted);
+    sort.fetch(ctx, query).await
 }
 
 pub async fn list_accounts_handler(
     user: AuthUser,
     State(ctx): State<Arc<ApiContext>>,
     page: Pagination,
+    filters: Filters,
+    fields: Fields,
+    expand: Expand,
+    IncludeDeleted(include_deleted): IncludeDeleted,
 ) -> Result<impl IntoResponse, Error> {
-    user.has_permission("list:account")?;
-    let users = list_accounts(&ctx, &page).await?;
-    Ok(Json(users))
+    fields.validate::<Accounts>()?;
+    expand.check(&user, ACCOUNT_EXPANSIONS)?;
+    let member = match user.has_permission(ACCOUNT_ADMIN) {
+        Ok(_) => None,
+        Err(_) => Some(user.user()?.id),
+    };
+    let mut page = list_accounts(&ctx, &page, &filters, member, include_deleted).await?;
+    let accounts = expand_accounts(&ctx, std::mem::take(&mut page.data), &expand).await?;
+    let accounts = accounts
+        .into_iter()
+        .map(|account| fields.apply(account, &expand))
+        .collect::<Result<_, _>>()?;
+    Ok(page.with_data(accounts))
 }
 
 pub async fn create_account_handler(
     user: AuthUser,
     State(ctx): State<Arc<ApiContext>>,
+    body: Result<Json<CreateAccount>, JsonRejection>,
 ) -> Result<impl IntoResponse, Error> {
-    user.has_permission("create:account")?;
-    let created = create_account(&ctx).await?;
+    let Json(body) = body?;
+    let created = create_account(&ctx, user.user()?, body).await?;
     Ok((StatusCode::CREATED, Json(created)))
 }
 
 pub async fn get_account_by_id_handler(
     user: AuthUser,
     State(ctx): State<Arc<ApiContext>>,
-    account_id: Result<Path<Uuid>, PathRejection>,
+    access: AccountAccess,
+    fields: Fields,
+    expand: Expand,
+    IncludeDeleted(include_deleted): IncludeDeleted,
+) -> Result<impl IntoResponse, Error> {
+    fields.validate::<Accounts>()?;
+    expand.check(&user, ACCOUNT_EXPANSIONS)?;
+    let account = get_account_by_id(&ctx, access.account_id, include_deleted).await?;
+    let account = expand_accounts(&ctx, vec![account], &expand)
+        .await?
+        .pop()
+        .ok_or(Error::NotFound)?;
+    Ok(Json(fields.apply(account, &expand)?))
+}
+
+pub async fn update_account_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    access: AccountAccess,
+    body: Result<Json<UpdateAccount>, JsonRejection>,
 ) -> Result<impl IntoResponse, Error> {
-    user.has_permission("retrieve:account")?;
-    let Path(account_id) = account_id?;
-    let account = get_account_by_id(&ctx, account_id).await?;
-    Ok(Json(account))
+    access.require_role(MembershipRole::Admin)?;
+    let Json(body) = body?;
+    if body.status.is_some() && !access.is_admin() {
+        return Err(Error::Forbidden);
+    }
+    let updated = update_account(&ctx, access.account_id, body).await?;
+    Ok(Json(updated))
 }
 
 pub async fn delete_account_handler(
-    user: AuthUser,
     State(ctx): State<Arc<ApiContext>>,
-    account_id: Result<Path<Uuid>, PathRejection>,
+    access: AccountAccess,
 ) -> Result<impl IntoResponse, Error> {
-    user.has_permission("delete:account")?;
-    let Path(account_id) = account_id?;
-    let deleted = delete_account(&ctx, account_id).await?;
+    access.require_role(MembershipRole::Owner)?;
+    let deleted = delete_account(&ctx, access.account_id).await?;
     Ok(Json(deleted))
 }
 
+pub async fn restore_account_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    access: AccountAccess,
+) -> Result<impl IntoResponse, Error> {
+    access.require_role(MembershipRole::Owner)?;
+    let restored = restore_account(&ctx, access.account_id).await?;
+    Ok(Json(restored))
+}
+
 pub async fn list_account_users_handler(
-    user: AuthUser,
     State(ctx): State<Arc<ApiContext>>,
-    account_id: Result<Path<Uuid>, PathRejection>,
+    access: AccountAccess,
     page: Pagination,
+    IncludeDeleted(include_deleted): IncludeDeleted,
 ) -> Result<impl IntoResponse, Error> {
-    user.has_permission("list:user:account")?;
-    let Path(account_id) = account_id?;
-    let users = list_account_users(&ctx, account_id, &page).await?;
-    Ok(Json(users))
+    let users = list_account_users(&ctx, access.account_id, &page, include_deleted).await?;
+    Ok(users)
 }
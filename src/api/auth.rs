@@ -2,22 +2,34 @@ use std::sync::Arc;
 
 use axum::{
     async_trait,
-    extract::{FromRef, FromRequestParts},
+    extract::{FromRef, FromRequestParts, MatchedPath, Request, State},
     http::{header, request::Parts, HeaderValue},
+    middleware::Next,
+    response::Response,
 };
 use jsonwebtoken::decode_header;
 
-use crate::{entity::users, error::Error};
+use crate::{auth0::AuthClaims, entity::users, error::Error};
 
 use super::{
-    users::{create_user, get_user_by_provider_id, CreateUser},
-    ApiContext,
+    permissions::grants, profiles::provision_user, users::get_user_by_provider_id, ApiContext,
 };
 
-#[derive(Debug)]
+/// The kind of caller a token was issued to
+#[derive(Debug, Clone)]
+pub enum Principal {
+    /// An end user, backed by a row in `users`
+    User(users::Model),
+    /// A machine-to-machine application using the client credentials grant.
+    /// Service principals have no `users` row.
+    Service { client_id: String },
+}
+
+#[derive(Debug, Clone)]
 pub struct AuthUser {
-    pub user: users::Model,
-    pub user_id: String,
+    pub principal: Principal,
+    /// The `sub` claim of the token
+    pub subject: String,
     pub permissions: Vec<String>,
 }
 
@@ -31,46 +43,100 @@ impl AuthUser {
             .map_err(|_| Error::Unauthorized)?
             .strip_prefix("Bearer ")
             .ok_or(Error::Unauthorized)?;
-        let header = decode_header(token).map_err(|_| Error::Unauthorized)?;
-        let kid = match header.kid {
-            Some(k) => k,
-            None => return Err(Error::Unauthorized),
-        };
-        let decoded_token = ctx.auth0_client.decode_token(kid, token)?;
-        let user_id = decoded_token.claims.sub;
-        let user = get_user_by_provider_id(ctx, &user_id).await;
-        let user = match user {
-            Ok(Some(user)) => user,
-            Ok(None) => create_user(
-                ctx,
-                CreateUser {
-                    provider_id: user_id.clone(),
-                    stripe_customer_id: String::from("placeholder"),
+        let claims = decode_claims(ctx, token)?;
+        if ctx.revocations.is_revoked(&claims) {
+            return Err(Error::Unauthorized);
+        }
+        let permissions = claims.permissions();
+        if let Some(client_id) = claims.client_id() {
+            return Ok(Self {
+                principal: Principal::Service {
+                    client_id: client_id.to_string(),
                 },
-            )
-            .await
-            .map_err(|_| Error::Unauthorized)?,
-            Err(_) => return Err(Error::Unauthorized),
+                subject: claims.sub,
+                permissions,
+            });
+        }
+        let user = match ctx.users.get(&claims.sub) {
+            Some(user) => user,
+            None => {
+                // Deleted users keep their provider id, so they're looked up
+                // too and rejected rather than provisioned again
+                let user = match get_user_by_provider_id(ctx, &claims.sub, true).await {
+                    Ok(Some(user)) => user,
+                    Ok(None) => provision_user(ctx, &claims.sub, token)
+                        .await
+                        .map_err(|_| Error::Unauthorized)?,
+                    Err(_) => return Err(Error::Unauthorized),
+                };
+                ctx.users.insert(user.clone());
+                user
+            }
         };
-        let permissions = decoded_token.claims.permissions;
+        if user.deleted.is_some() {
+            return Err(Error::Unauthorized);
+        }
         Ok(Self {
-            user,
-            user_id,
+            principal: Principal::User(user),
+            subject: claims.sub,
             permissions,
         })
     }
+
+    /// The `users` row of the caller, service principals have none
+    pub fn user(&self) -> Result<&users::Model, Error> {
+        match &self.principal {
+            Principal::User(user) => Ok(user),
+            Principal::Service { .. } => Err(Error::Forbidden),
+        }
+    }
+
+    /// Whether the caller is a machine-to-machine application
+    pub fn is_service(&self) -> bool {
+        matches!(self.principal, Principal::Service { .. })
+    }
+
     pub fn has_permission(&self, permission: &str) -> Result<bool, Error> {
-        match self
-            .permissions
-            .iter()
-            .find(|p| p == &&permission.to_string())
-        {
+        match self.permissions.iter().find(|p| grants(p, permission)) {
             Some(_) => Ok(true),
             None => Err(Error::Forbidden),
         }
     }
 }
 
+/// Validate a raw JWT against the cached JWK set and return its claims
+pub fn decode_claims(ctx: &ApiContext, token: &str) -> Result<AuthClaims, Error> {
+    let header = decode_header(token).map_err(|_| Error::Unauthorized)?;
+    let kid = header.kid.ok_or(Error::Unauthorized)?;
+    Ok(ctx.auth0_client.decode_token(kid, token)?.claims)
+}
+
+/// Middleware that authenticates the caller when an `Authorization` header
+/// is present, making the `AuthUser` available to route layers and handlers
+/// through the request extensions. Requests without the header pass through
+/// unauthenticated and are rejected by routes that require a permission.
+///
+/// Public routes never look at the caller, so the header is ignored there
+/// and a stray one can't fail a healthcheck or webhook.
+pub async fn authenticate(
+    State(ctx): State<Arc<ApiContext>>,
+    path: Option<MatchedPath>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, Error> {
+    let public = path
+        .and_then(|path| ctx.public_routes.get(path.as_str()))
+        .is_some_and(|methods| methods.contains(req.method()));
+    if public {
+        return Ok(next.run(req).await);
+    }
+    if let Some(auth_header) = req.headers().get(header::AUTHORIZATION) {
+        let user = AuthUser::from_authorization(&ctx, auth_header).await?;
+        req.extensions_mut().insert(user);
+    }
+    Ok(next.run(req).await)
+}
+
 #[async_trait]
 impl<S> FromRequestParts<S> for AuthUser
 where
@@ -79,6 +145,9 @@ where
 {
     type Rejection = Error;
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(user) = parts.extensions.get::<Self>() {
+            return Ok(user.clone());
+        }
         let ctx = Arc::from_ref(state);
         if let Some(auth_header) = parts.headers.get(header::AUTHORIZATION) {
             Ok(Self::from_authorization(&ctx, auth_header).await?)
@@ -87,3 +156,51 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, StatusCode},
+        routing::post,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{api::app, config::Config};
+
+    fn request(path: &str) -> Request {
+        axum::http::Request::get(path)
+            .header(header::AUTHORIZATION, "Bearer invalid")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_public_routes_skip_authentication() {
+        let ctx = Arc::new(ApiContext::for_tests(Config::default()));
+        let app = app(super::super::routes(), ctx);
+        let response = app.clone().oneshot(request("/health")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(request("/v1/users")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_public_routes_match_method() {
+        let routes = super::super::routes().authenticated_route("/health", post(|| async {}));
+        let ctx = ApiContext {
+            public_routes: routes.public_methods(),
+            ..ApiContext::for_tests(Config::default())
+        };
+        let app = app(routes, Arc::new(ctx));
+        let mut req = request("/health");
+        *req.method_mut() = Method::POST;
+        let response = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let mut req = request("/health");
+        *req.method_mut() = Method::HEAD;
+        let response = app.oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
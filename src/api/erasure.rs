@@ -0,0 +1,247 @@
+use std::sync::Arc;
+
+use ::stripe::{Customer, CustomerId, StripeError};
+use axum::{
+    extract::{rejection::PathRejection, Path, State},
+    response::IntoResponse,
+    routing::{get, post},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use sea_orm::{entity::*, query::*, sea_query::Expr, DatabaseTransaction};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::entity::{
+    invitations,
+    prelude::*,
+    revocations::{self, RevocationKind},
+    soft_delete::SoftDeleteFilter,
+    tombstones::{self, TombstoneKind},
+    users,
+    users_accounts::{self, MembershipRole},
+};
+use crate::error::Error;
+use crate::revocation::Revocation;
+
+use super::{
+    auth::AuthUser,
+    memberships::ensure_not_sole_owner,
+    pagination::{Page, Pagination},
+    permissions::{Permission, Routes},
+    ApiContext,
+};
+
+/// Users purged in a single run of the purge job
+const PURGE_BATCH: u64 = 100;
+
+/// Whether a user is the only owner of an active account. Such users are
+/// left out of purges so they don't hold up everyone deleted after them.
+const SOLE_OWNER: &str = r#"EXISTS (
+    SELECT 1 FROM "users_accounts" AS "owned"
+    JOIN "accounts" ON "accounts"."id" = "owned"."account_id" AND "accounts"."deleted" IS NULL
+    WHERE "owned"."user_id" = "users"."id"
+        AND "owned"."role" = $1
+        AND "owned"."deleted" IS NULL
+        AND NOT EXISTS (
+            SELECT 1 FROM "users_accounts" AS "other"
+            WHERE "other"."account_id" = "owned"."account_id"
+                AND "other"."user_id" <> "owned"."user_id"
+                AND "other"."role" = $1
+                AND "other"."deleted" IS NULL
+        )
+)"#;
+
+/// How long tokens of an erased user stay blocked, long enough for any
+/// access token issued before the erasure to expire
+const ERASED_TOKEN_TTL: Duration = Duration::days(1);
+
+pub fn routes() -> Routes {
+    Routes::new()
+        .route_with_permission(
+            "/v1/users/:id/erase",
+            post(erase_user_handler),
+            Permission::AllOf(&["erase:user"]),
+        )
+        .route_with_permission(
+            "/v1/tombstones",
+            get(list_tombstones_handler),
+            Permission::AllOf(&["list:tombstone"]),
+        )
+}
+
+/// Delete the user's Stripe customer, customers that are already gone or
+/// were never created are skipped
+async fn delete_stripe_customer(ctx: &ApiContext, user: &users::Model) -> Result<(), Error> {
+    let Ok(customer_id) = user.stripe_customer_id.parse::<CustomerId>() else {
+        return Ok(());
+    };
+    match Customer::delete(&ctx.stripe_client, &customer_id).await {
+        Ok(_) => Ok(()),
+        Err(StripeError::Stripe(e)) if e.http_status == 404 => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Load a user that can be erased, failing if they're the only owner of an
+/// account, which would be left without one
+async fn find_erasable_user(
+    txn: &DatabaseTransaction,
+    id: Uuid,
+    lock: bool,
+) -> Result<users::Model, Error> {
+    let mut query = Users::find_by_id(id);
+    if lock {
+        query = query.lock_exclusive();
+    }
+    let user = query.one(txn).await?.ok_or(Error::NotFound)?;
+    ensure_not_sole_owner(txn, user.id).await?;
+    Ok(user)
+}
+
+/// Permanently remove a user, along with their memberships, identity
+/// provider account and Stripe customer, leaving only a tombstone. Users
+/// who are the only owner of an account can't be erased.
+///
+/// The external deletions run before the user row is locked, so no lock is
+/// held across them. They're idempotent, so a failed erasure can simply be
+/// retried.
+pub async fn erase_user(
+    ctx: &ApiContext,
+    id: Uuid,
+    kind: TombstoneKind,
+    requested_by: Option<String>,
+) -> Result<tombstones::Model, Error> {
+    // Checked up front so a refused erasure leaves external accounts alone
+    let txn = ctx.db.begin().await?;
+    let user = find_erasable_user(&txn, id, false).await?;
+    txn.rollback().await?;
+
+    delete_stripe_customer(ctx, &user).await?;
+    if !user.provider_id.is_empty() && !ctx.config.auth0_domain.is_empty() {
+        ctx.auth0_client.delete_user(&user.provider_id).await?;
+    }
+
+    // Checked again under lock in case ownership changed in the meantime
+    let txn = ctx.db.begin().await?;
+    let user = find_erasable_user(&txn, id, true).await?;
+    UsersAccounts::delete_many()
+        .filter(users_accounts::Column::UserId.eq(user.id))
+        .exec(&txn)
+        .await?;
+    Invitations::update_many()
+        .col_expr(invitations::Column::InvitedBy, Expr::value(None::<Uuid>))
+        .filter(invitations::Column::InvitedBy.eq(user.id))
+        .exec(&txn)
+        .await?;
+    Invitations::update_many()
+        .col_expr(invitations::Column::AcceptedBy, Expr::value(None::<Uuid>))
+        .filter(invitations::Column::AcceptedBy.eq(user.id))
+        .exec(&txn)
+        .await?;
+    // Tokens still in flight would otherwise provision the user again
+    let now = Utc::now();
+    revocations::ActiveModel {
+        id: Set(Uuid::now_v7()),
+        kind: Set(RevocationKind::Subject),
+        subject: Set(Some(user.provider_id.clone())),
+        reason: Set(Some(String::from("user erased"))),
+        expires: Set(Some(DateTime::from(now + ERASED_TOKEN_TTL))),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    Users::delete_by_id(user.id).exec(&txn).await?;
+    let tombstone = tombstones::ActiveModel {
+        id: Set(Uuid::now_v7()),
+        user_id: Set(user.id),
+        kind: Set(kind),
+        requested_by: Set(requested_by),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    txn.commit().await?;
+
+    ctx.users.invalidate(&user.provider_id);
+    ctx.revocations
+        .insert(Revocation::Subject(user.provider_id));
+    Ok(tombstone)
+}
+
+/// Users soft deleted before `cutoff` who can be purged, oldest first
+fn purgeable_users(cutoff: DateTime<Utc>) -> Select<Users> {
+    Users::find()
+        .deleted::<Users>()
+        .filter(users::Column::Deleted.lt(cutoff))
+        .filter(Expr::cust_with_values(SOLE_OWNER, [MembershipRole::Owner as i32]).not())
+        .order_by_asc(users::Column::Deleted)
+        .limit(PURGE_BATCH)
+}
+
+/// Erase users that have been soft deleted for longer than the retention
+/// period, returning how many were purged. Failures are logged and retried
+/// on the next run. Users who are still an account's only owner are skipped
+/// until another owner is added.
+pub async fn purge_deleted_users(ctx: &ApiContext) -> Result<usize, Error> {
+    let cutoff = Utc::now() - Duration::days(ctx.config.deleted_user_retention_days.into());
+    let expired = purgeable_users(cutoff).all(&ctx.db).await?;
+    let mut purged = 0;
+    for user in expired {
+        match erase_user(ctx, user.id, TombstoneKind::Purged, None).await {
+            Ok(_) => purged += 1,
+            // Ownership may have changed since the users were listed
+            Err(Error::Conflict(reason)) => {
+                warn!("Skipped purging user {}: {}", user.id, reason);
+            }
+            Err(e) => error!("Failed to purge user {}: {:?}", user.id, e),
+        }
+    }
+    if purged > 0 {
+        info!("Purged {} deleted users", purged);
+    }
+    Ok(purged)
+}
+
+pub async fn list_tombstones(
+    ctx: &Arc<ApiContext>,
+    page: &Pagination,
+) -> Result<Page<tombstones::Model>, Error> {
+    let sort = page.sort(tombstones::Column::Id, &[])?;
+    sort.fetch(ctx, Tombstones::find()).await
+}
+
+async fn erase_user_handler(
+    user: AuthUser,
+    State(ctx): State<Arc<ApiContext>>,
+    user_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Path(user_id) = user_id?;
+    let tombstone = erase_user(&ctx, user_id, TombstoneKind::Erased, Some(user.subject)).await?;
+    Ok(Json(tombstone))
+}
+
+async fn list_tombstones_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    page: Pagination,
+) -> Result<impl IntoResponse, Error> {
+    let tombstones = list_tombstones(&ctx, &page).await?;
+    Ok(tombstones)
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::DbBackend;
+
+    use super::*;
+
+    #[test]
+    fn test_purgeable_users_skip_sole_owners() {
+        let sql = purgeable_users(Utc::now())
+            .build(DbBackend::Postgres)
+            .to_string();
+        assert!(sql.contains("AND (NOT (EXISTS ("), "{sql}");
+        assert!(sql.contains(r#""owned"."role" = 2"#), "{sql}");
+        assert!(sql.contains(r#""other"."role" = 2"#), "{sql}");
+    }
+}
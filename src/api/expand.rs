@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+    RequestPartsExt,
+};
+use sea_orm::{EntityTrait, IdenStatic, Iterable};
+use serde::Serialize;
+
+use crate::error::Error;
+
+use super::auth::AuthUser;
+
+/// Deepest relation path that can be expanded, e.g. `accounts.subscription`
+const MAX_DEPTH: usize = 2;
+
+/// Parse a comma separated query parameter
+fn parse_list(params: &HashMap<String, String>, name: &str) -> Option<Vec<String>> {
+    let list = params.get(name)?;
+    Some(
+        list.split(',')
+            .filter(|item| !item.is_empty())
+            .map(ToString::to_string)
+            .collect(),
+    )
+}
+
+/// Sparse fieldset, `?fields=id,name`, trimming a resource down to the
+/// fields listed. Expanded relations are always kept.
+#[derive(Debug, Clone, Default)]
+pub struct Fields(Option<Vec<String>>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Fields
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Query(params) = parts.extract::<Query<HashMap<String, String>>>().await?;
+        Ok(Self(parse_list(&params, "fields")))
+    }
+}
+
+impl Fields {
+    /// Check the fields requested are columns of `E`
+    pub fn validate<E: EntityTrait>(&self) -> Result<(), Error> {
+        for field in self.0.iter().flatten() {
+            if !E::Column::iter().any(|column| column.as_str() == field) {
+                return Err(Error::BadRequest(format!("unknown field `{field}`")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Serialize a resource, keeping only the requested fields and
+    /// expanded relations
+    pub fn apply(
+        &self,
+        resource: impl Serialize,
+        expand: &Expand,
+    ) -> Result<serde_json::Value, Error> {
+        let mut value = serde_json::to_value(resource)?;
+        if let (Some(fields), serde_json::Value::Object(object)) = (&self.0, &mut value) {
+            object.retain(|key, _| fields.contains(key) || expand.contains(key));
+        }
+        Ok(value)
+    }
+}
+
+/// Related resources to embed, `?expand=users,subscription`. Nested
+/// relations are expanded with dotted paths up to [`MAX_DEPTH`] deep.
+#[derive(Debug, Clone, Default)]
+pub struct Expand(Vec<String>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Expand
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Query(params) = parts.extract::<Query<HashMap<String, String>>>().await?;
+        let paths = parse_list(&params, "expand").unwrap_or_default();
+        if let Some(path) = paths
+            .iter()
+            .find(|path| path.split('.').count() > MAX_DEPTH)
+        {
+            return Err(Error::BadRequest(format!(
+                "can't expand `{path}`, relations can be expanded at most {MAX_DEPTH} deep"
+            )));
+        }
+        Ok(Self(paths))
+    }
+}
+
+impl Expand {
+    /// Check the requested relations against those a resource allows, each
+    /// with the permission needed to see it. Nested paths also need the
+    /// permissions of their parents.
+    pub fn check(&self, user: &AuthUser, expandable: &[(&str, &str)]) -> Result<(), Error> {
+        for path in &self.0 {
+            let prefixes = path
+                .match_indices('.')
+                .map(|(i, _)| &path[..i])
+                .chain([path.as_str()]);
+            for prefix in prefixes {
+                let (_, permission) = expandable
+                    .iter()
+                    .find(|(name, _)| *name == prefix)
+                    .ok_or_else(|| Error::BadRequest(format!("can't expand `{prefix}`")))?;
+                user.has_permission(permission)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a relation was requested, directly or through a nested path
+    pub fn contains(&self, relation: &str) -> bool {
+        self.0.iter().any(|path| {
+            path == relation
+                || path
+                    .strip_prefix(relation)
+                    .is_some_and(|rest| rest.starts_with('.'))
+        })
+    }
+
+    /// The paths requested below a relation
+    pub fn nested(&self, relation: &str) -> Self {
+        let paths = self
+            .0
+            .iter()
+            .filter_map(|path| path.strip_prefix(relation)?.strip_prefix('.'))
+            .map(ToString::to_string)
+            .collect();
+        Self(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::entity::prelude::*;
+
+    fn expand(paths: &[&str]) -> Expand {
+        Expand(paths.iter().map(ToString::to_string).collect())
+    }
+
+    #[test]
+    fn test_contains_and_nested() {
+        let expand = expand(&["accounts.subscription", "usersx"]);
+        assert!(expand.contains("accounts"));
+        assert!(!expand.contains("users"));
+        assert!(!expand.contains("subscription"));
+        let nested = expand.nested("accounts");
+        assert!(nested.contains("subscription"));
+        assert!(!nested.contains("accounts"));
+    }
+
+    #[test]
+    fn test_validate_fields() {
+        let fields = Fields(Some(vec![String::from("id"), String::from("name")]));
+        assert!(fields.validate::<Accounts>().is_ok());
+        let fields = Fields(Some(vec![String::from("password")]));
+        assert!(fields.validate::<Accounts>().is_err());
+    }
+
+    #[test]
+    fn test_apply_fields() {
+        let resource = json!({ "id": 1, "name": "Acme", "status": "Active", "users": [] });
+        let fields = Fields(Some(vec![String::from("name")]));
+        assert_eq!(
+            fields.apply(&resource, &expand(&["users"])).unwrap(),
+            json!({ "name": "Acme", "users": [] })
+        );
+        assert_eq!(
+            Fields::default().apply(&resource, &expand(&[])).unwrap(),
+            resource
+        );
+    }
+}
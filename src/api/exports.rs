@@ -0,0 +1,306 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        rejection::{PathRejection, QueryRejection},
+        Path, Query, State,
+    },
+    http::{header, Method, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sea_orm::{entity::*, query::*, sea_query::Expr};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::entity::{
+    accounts,
+    exports::{self, ExportStatus},
+    invitations,
+    prelude::*,
+    revocations,
+    soft_delete::SoftDeleteFilter,
+    subscriptions, users, users_accounts,
+};
+use crate::error::Error;
+
+use super::{
+    auth::AuthUser,
+    permissions::{Permission, Routes},
+    tasks::enqueue,
+    ApiContext,
+};
+
+/// Name of the task that generates export bundles
+pub const EXPORT_TASK: &str = "export_user";
+
+/// Purpose export download tokens are signed for
+const EXPORT_TOKEN: &str = "export";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportPayload {
+    export_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadExport {
+    pub token: String,
+}
+
+/// An export along with a signed link to download it once it's ready
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportWithLink {
+    #[serde(flatten)]
+    pub export: exports::Model,
+    pub download_url: Option<String>,
+}
+
+/// Everything stored about a user, soft deleted rows included. Invitations
+/// and revocations are the events recorded against a user.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportBundle {
+    pub generated: DateTime<Utc>,
+    pub user: users::Model,
+    pub memberships: Vec<users_accounts::Model>,
+    pub accounts: Vec<accounts::Model>,
+    pub subscriptions: Vec<subscriptions::Model>,
+    pub invitations: Vec<invitations::Model>,
+    pub revocations: Vec<revocations::Model>,
+}
+
+pub fn routes() -> Routes {
+    Routes::new()
+        .route_with_permission(
+            "/v1/users/:id/export",
+            get(get_export_handler).post(create_export_handler),
+            Permission::AllOf(&["export:user"]),
+        )
+        .authenticated_route(
+            "/v1/me/export",
+            get(get_my_export_handler).post(create_my_export_handler),
+        )
+        .public_route("/v1/exports/download", Method::GET, download_export_handler)
+}
+
+/// Request an export of a user's data, generated in the background. A
+/// pending export is returned instead of queueing another.
+pub async fn create_export(
+    ctx: &ApiContext,
+    user_id: Uuid,
+    requested_by: String,
+) -> Result<exports::Model, Error> {
+    let txn = ctx.db.begin().await?;
+    Users::find_by_id(user_id)
+        .active::<Users>()
+        .lock_exclusive()
+        .one(&txn)
+        .await?
+        .ok_or(Error::NotFound)?;
+    let pending = Exports::find()
+        .filter(exports::Column::UserId.eq(user_id))
+        .filter(exports::Column::Status.eq(ExportStatus::Pending))
+        .one(&txn)
+        .await?;
+    if let Some(pending) = pending {
+        return Ok(pending);
+    }
+    let export = exports::ActiveModel {
+        id: Set(Uuid::now_v7()),
+        user_id: Set(user_id),
+        requested_by: Set(Some(requested_by)),
+        status: Set(ExportStatus::Pending),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    enqueue(
+        &txn,
+        EXPORT_TASK,
+        ExportPayload {
+            export_id: export.id,
+        },
+    )
+    .await?;
+    txn.commit().await?;
+    Ok(export)
+}
+
+/// The most recent export of a user, with a download link when it's ready
+pub async fn get_latest_export(ctx: &ApiContext, user_id: Uuid) -> Result<ExportWithLink, Error> {
+    let export = Exports::find()
+        .filter(exports::Column::UserId.eq(user_id))
+        .order_by_desc(exports::Column::Created)
+        .one(&ctx.db)
+        .await?
+        .ok_or(Error::NotFound)?;
+    let download_url = match (export.status, export.expires) {
+        (ExportStatus::Ready, Some(expires)) if expires > Utc::now() => {
+            let token = ctx
+                .signer
+                .sign(EXPORT_TOKEN, &export.id.to_string(), expires.into())?;
+            Some(format!("/v1/exports/download?token={token}"))
+        }
+        _ => None,
+    };
+    Ok(ExportWithLink {
+        export,
+        download_url,
+    })
+}
+
+async fn build_bundle(ctx: &ApiContext, user: users::Model) -> Result<ExportBundle, Error> {
+    let memberships = UsersAccounts::find()
+        .filter(users_accounts::Column::UserId.eq(user.id))
+        .all(&ctx.db)
+        .await?;
+    let account_ids: Vec<Uuid> = memberships.iter().map(|m| m.account_id).collect();
+    let accounts = Accounts::find()
+        .filter(accounts::Column::Id.is_in(account_ids.clone()))
+        .all(&ctx.db)
+        .await?;
+    let subscriptions = Subscriptions::find()
+        .filter(subscriptions::Column::AccountId.is_in(account_ids))
+        .all(&ctx.db)
+        .await?;
+    let invitations = Invitations::find()
+        .filter(
+            Condition::any()
+                .add(invitations::Column::InvitedBy.eq(user.id))
+                .add(invitations::Column::AcceptedBy.eq(user.id)),
+        )
+        .all(&ctx.db)
+        .await?;
+    let revocations = Revocations::find()
+        .filter(revocations::Column::Subject.eq(user.provider_id.clone()))
+        .all(&ctx.db)
+        .await?;
+    Ok(ExportBundle {
+        generated: Utc::now(),
+        user,
+        memberships,
+        accounts,
+        subscriptions,
+        invitations,
+        revocations,
+    })
+}
+
+/// Task handler that assembles the bundle for a pending export
+pub async fn generate_export(ctx: &ApiContext, payload: &serde_json::Value) -> Result<(), Error> {
+    let payload: ExportPayload = serde_json::from_value(payload.clone())?;
+    let export = Exports::find_by_id(payload.export_id)
+        .filter(exports::Column::Status.eq(ExportStatus::Pending))
+        .one(&ctx.db)
+        .await?;
+    // The export or its user may have been removed in the meantime
+    let Some(export) = export else {
+        return Ok(());
+    };
+    let Some(user) = Users::find_by_id(export.user_id).one(&ctx.db).await? else {
+        return Ok(());
+    };
+    let bundle = build_bundle(ctx, user).await?;
+    let mut export: exports::ActiveModel = export.into();
+    let now = Utc::now();
+    export.data = Set(Some(serde_json::to_value(bundle)?));
+    export.status = Set(ExportStatus::Ready);
+    export.expires = Set(Some(DateTime::from(now + ctx.config.export_ttl)));
+    export.updated = Set(DateTime::from(now));
+    export.update(&ctx.db).await?;
+    Ok(())
+}
+
+/// Mark an export as failed once its task has run out of attempts
+pub async fn fail_export(ctx: &ApiContext, payload: &serde_json::Value) -> Result<(), Error> {
+    let payload: ExportPayload = serde_json::from_value(payload.clone())?;
+    Exports::update_many()
+        .col_expr(exports::Column::Status, Expr::value(ExportStatus::Failed))
+        .col_expr(exports::Column::Updated, Expr::value(Utc::now()))
+        .filter(exports::Column::Id.eq(payload.export_id))
+        .exec(&ctx.db)
+        .await?;
+    Ok(())
+}
+
+/// Drop the bundles of exports whose download link has expired
+pub async fn expire_exports(ctx: &ApiContext) -> Result<(), Error> {
+    let now = Utc::now();
+    Exports::update_many()
+        .col_expr(exports::Column::Status, Expr::value(ExportStatus::Expired))
+        .col_expr(
+            exports::Column::Data,
+            Expr::value(None::<serde_json::Value>),
+        )
+        .col_expr(exports::Column::Updated, Expr::value(now))
+        .filter(exports::Column::Status.eq(ExportStatus::Ready))
+        .filter(exports::Column::Expires.lt(now))
+        .exec(&ctx.db)
+        .await?;
+    Ok(())
+}
+
+/// Fetch the bundle a signed download token points to
+pub async fn download_export(ctx: &ApiContext, token: &str) -> Result<serde_json::Value, Error> {
+    let id = ctx.signer.verify(EXPORT_TOKEN, token)?;
+    let id = Uuid::parse_str(&id).map_err(|_| Error::NotFound)?;
+    let export = Exports::find_by_id(id)
+        .filter(exports::Column::Status.eq(ExportStatus::Ready))
+        .filter(exports::Column::Expires.gt(Utc::now()))
+        .one(&ctx.db)
+        .await?
+        .ok_or(Error::NotFound)?;
+    export.data.ok_or(Error::NotFound)
+}
+
+async fn create_export_handler(
+    user: AuthUser,
+    State(ctx): State<Arc<ApiContext>>,
+    user_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Path(user_id) = user_id?;
+    let export = create_export(&ctx, user_id, user.subject).await?;
+    Ok((StatusCode::ACCEPTED, Json(export)))
+}
+
+async fn get_export_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    user_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Path(user_id) = user_id?;
+    let export = get_latest_export(&ctx, user_id).await?;
+    Ok(Json(export))
+}
+
+async fn create_my_export_handler(
+    user: AuthUser,
+    State(ctx): State<Arc<ApiContext>>,
+) -> Result<impl IntoResponse, Error> {
+    let user_id = user.user()?.id;
+    let export = create_export(&ctx, user_id, user.subject).await?;
+    Ok((StatusCode::ACCEPTED, Json(export)))
+}
+
+async fn get_my_export_handler(
+    user: AuthUser,
+    State(ctx): State<Arc<ApiContext>>,
+) -> Result<impl IntoResponse, Error> {
+    let export = get_latest_export(&ctx, user.user()?.id).await?;
+    Ok(Json(export))
+}
+
+async fn download_export_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    query: Result<Query<DownloadExport>, QueryRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Query(query) = query?;
+    let bundle = download_export(&ctx, &query.token).await?;
+    Ok((
+        [(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"export.json\"",
+        )],
+        Json(bundle),
+    ))
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+    RequestPartsExt,
+};
+use chrono::{DateTime, NaiveDate};
+use sea_orm::{ColumnTrait, Condition, Value};
+
+use crate::error::Error;
+
+/// Parses a filter value into a database value, `None` when it's invalid
+pub type ParseValue = fn(&str) -> Option<Value>;
+
+/// Comparison applied by a filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    In,
+}
+
+impl Operator {
+    fn parse(operator: &str) -> Option<Self> {
+        match operator {
+            "eq" => Some(Self::Eq),
+            "ne" => Some(Self::Ne),
+            "gt" => Some(Self::Gt),
+            "gte" => Some(Self::Gte),
+            "lt" => Some(Self::Lt),
+            "lte" => Some(Self::Lte),
+            "in" => Some(Self::In),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Filter {
+    field: String,
+    operator: Operator,
+    value: String,
+}
+
+/// Filters on a listing, `?filter[status]=active&filter[created][gte]=2024-01-01`.
+/// A field without an operator is compared for equality, and `in` takes a
+/// comma separated list. Fields are checked against the listing's allowlist
+/// once it's known, see [`Filters::condition`].
+#[derive(Debug, Clone, Default)]
+pub struct Filters(Vec<Filter>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Filters
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Query(params) = parts.extract::<Query<HashMap<String, String>>>().await?;
+        let mut filters = params
+            .into_iter()
+            .filter(|(key, _)| key.starts_with("filter"))
+            .map(|(key, value)| parse_filter(&key, value))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Query parameters come out of the map in any order
+        filters.sort_by(|a, b| a.field.cmp(&b.field));
+        Ok(Self(filters))
+    }
+}
+
+/// Parse a `filter[field]` or `filter[field][operator]` parameter
+fn parse_filter(key: &str, value: String) -> Result<Filter, Error> {
+    let invalid = || Error::BadRequest(format!("`{key}` isn't a valid filter"));
+    let rest = key.strip_prefix("filter[").ok_or_else(invalid)?;
+    let (field, rest) = rest.split_once(']').ok_or_else(invalid)?;
+    let operator = match rest {
+        "" => Operator::Eq,
+        _ => {
+            let operator = rest
+                .strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+                .ok_or_else(invalid)?;
+            Operator::parse(operator).ok_or_else(|| {
+                Error::BadRequest(format!(
+                    "unknown operator `{operator}` for `{field}`, \
+                     expected one of eq, ne, gt, gte, lt, lte or in"
+                ))
+            })?
+        }
+    };
+    if field.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Filter {
+        field: field.to_string(),
+        operator,
+        value,
+    })
+}
+
+impl Filters {
+    /// Resolve the filters against the fields a listing allows, each with
+    /// its column and how its values are parsed
+    pub fn condition<C: ColumnTrait>(
+        &self,
+        filterable: &[(&str, C, ParseValue)],
+    ) -> Result<Condition, Error> {
+        let mut condition = Condition::all();
+        for filter in &self.0 {
+            let (_, column, parse) = filterable
+                .iter()
+                .find(|(name, _, _)| *name == filter.field)
+                .ok_or_else(|| Error::BadRequest(format!("can't filter by `{}`", filter.field)))?;
+            let value = |value: &str| {
+                parse(value).ok_or_else(|| {
+                    Error::BadRequest(format!(
+                        "`{value}` isn't a valid value for `{}`",
+                        filter.field
+                    ))
+                })
+            };
+            condition = condition.add(match filter.operator {
+                Operator::Eq => column.eq(value(&filter.value)?),
+                Operator::Ne => column.ne(value(&filter.value)?),
+                Operator::Gt => column.gt(value(&filter.value)?),
+                Operator::Gte => column.gte(value(&filter.value)?),
+                Operator::Lt => column.lt(value(&filter.value)?),
+                Operator::Lte => column.lte(value(&filter.value)?),
+                Operator::In => {
+                    let values = filter.value.split(',').map(value);
+                    column.is_in(values.collect::<Result<Vec<_>, _>>()?)
+                }
+            });
+        }
+        Ok(condition)
+    }
+}
+
+pub fn text(value: &str) -> Option<Value> {
+    Some(Value::from(value))
+}
+
+pub fn boolean(value: &str) -> Option<Value> {
+    value.parse::<bool>().ok().map(Value::from)
+}
+
+/// An RFC 3339 timestamp, or a date taken as midnight UTC
+pub fn timestamp(value: &str) -> Option<Value> {
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .or_else(|| {
+            let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+            Some(date.and_hms_opt(0, 0, 0)?.and_utc().fixed_offset())
+        })
+        .map(Value::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{DbBackend, EntityTrait, QueryFilter, QueryTrait};
+
+    use super::*;
+    use crate::entity::{prelude::*, users};
+
+    const FILTERABLE: &[(&str, users::Column, ParseValue)] = &[
+        ("email_verified", users::Column::EmailVerified, boolean),
+        ("created", users::Column::Created, timestamp),
+        ("provider_id", users::Column::ProviderId, text),
+    ];
+
+    fn filter(field: &str, operator: Operator, value: &str) -> Filter {
+        Filter {
+            field: field.to_string(),
+            operator,
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(
+            parse_filter("filter[status]", String::from("active")).unwrap(),
+            filter("status", Operator::Eq, "active")
+        );
+        assert_eq!(
+            parse_filter("filter[created][gte]", String::from("2024-01-01")).unwrap(),
+            filter("created", Operator::Gte, "2024-01-01")
+        );
+        assert!(parse_filter("filter[created][like]", String::new()).is_err());
+        assert!(parse_filter("filter[created", String::new()).is_err());
+        assert!(parse_filter("filter[]", String::new()).is_err());
+        assert!(parse_filter("filters", String::new()).is_err());
+    }
+
+    #[test]
+    fn test_condition() {
+        let filters = Filters(vec![
+            filter("created", Operator::Gte, "2024-01-01"),
+            filter("email_verified", Operator::Eq, "true"),
+            filter("provider_id", Operator::In, "auth0|1,auth0|2"),
+        ]);
+        let sql = Users::find()
+            .filter(filters.condition(FILTERABLE).unwrap())
+            .build(DbBackend::Postgres)
+            .to_string();
+        assert!(
+            sql.ends_with(
+                r#"WHERE "users"."created" >= '2024-01-01 00:00:00.000000 +00:00' AND "users"."email_verified" = TRUE AND "users"."provider_id" IN ('auth0|1', 'auth0|2')"#
+            ),
+            "{sql}"
+        );
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let filters = Filters(vec![filter("stripe_customer_id", Operator::Eq, "cus_1")]);
+        assert!(filters.condition(FILTERABLE).is_err());
+    }
+
+    #[test]
+    fn test_invalid_value() {
+        let filters = Filters(vec![filter("created", Operator::Lt, "yesterday")]);
+        assert!(filters.condition(FILTERABLE).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection},
+        Path, State,
+    },
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sea_orm::{entity::*, query::*};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::entity::{
+    invitations,
+    prelude::*,
+    users,
+    users_accounts::{self, MembershipRole},
+};
+use crate::error::Error;
+
+use super::{
+    auth::AuthUser,
+    memberships::{add_member, AccountAccess},
+    pagination::{Page, Pagination},
+    permissions::{Permission, Routes},
+    validation::validate_email,
+    ApiContext,
+};
+
+/// Purpose invitation tokens are signed for
+const INVITATION_TOKEN: &str = "invitation";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateInvitation {
+    pub email: String,
+    pub role: MembershipRole,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AcceptInvitation {
+    pub token: String,
+}
+
+/// A newly created invitation along with the token to deliver to the
+/// invitee. The token is only available at creation.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IssuedInvitation {
+    #[serde(flatten)]
+    pub invitation: invitations::Model,
+    pub token: String,
+}
+
+pub fn routes() -> Routes {
+    Routes::new()
+        .route_with_permission(
+            "/v1/accounts/:id/invitations",
+            get(list_invitations_handler),
+            Permission::AllOf(&["list:invitation"]),
+        )
+        .route_with_permission(
+            "/v1/accounts/:id/invitations",
+            post(create_invitation_handler),
+            Permission::AllOf(&["create:invitation"]),
+        )
+        .route_with_permission(
+            "/v1/accounts/:id/invitations/:invitation_id",
+            delete(revoke_invitation_handler),
+            Permission::AllOf(&["delete:invitation"]),
+        )
+        .authenticated_route("/v1/invitations/accept", post(accept_invitation_handler))
+}
+
+/// List invitations to an account that are still waiting to be accepted
+pub async fn list_invitations(
+    ctx: &Arc<ApiContext>,
+    account_id: Uuid,
+    page: &Pagination,
+) -> Result<Page<invitations::Model>, Error> {
+    let sort = page.sort(invitations::Column::Id, &[])?;
+    let query = Invitations::find()
+        .filter(invitations::Column::AccountId.eq(account_id))
+        .filter(invitations::Column::Accepted.is_null())
+        .filter(invitations::Column::Revoked.is_null())
+        .filter(invitations::Column::Expires.gt(Utc::now()));
+    sort.fetch(ctx, query).await
+}
+
+pub async fn create_invitation(
+    ctx: &Arc<ApiContext>,
+    account_id: Uuid,
+    invited_by: Option<Uuid>,
+    invitation: CreateInvitation,
+) -> Result<IssuedInvitation, Error> {
+    let email = validate_email(&invitation.email)?;
+    let expires = Utc::now() + ctx.config.invitation_ttl;
+    let invitation = invitations::ActiveModel {
+        id: Set(Uuid::now_v7()),
+        account_id: Set(account_id),
+        email: Set(email),
+        role: Set(invitation.role),
+        invited_by: Set(invited_by),
+        expires: Set(DateTime::from(expires)),
+        ..Default::default()
+    };
+    let invitation = invitation.insert(&ctx.db).await?;
+    let token = ctx
+        .signer
+        .sign(INVITATION_TOKEN, &invitation.id.to_string(), expires)?;
+    Ok(IssuedInvitation { invitation, token })
+}
+
+pub async fn revoke_invitation(
+    ctx: &Arc<ApiContext>,
+    account_id: Uuid,
+    id: Uuid,
+) -> Result<invitations::Model, Error> {
+    let invitation = Invitations::find_by_id(id)
+        .filter(invitations::Column::AccountId.eq(account_id))
+        .one(&ctx.db)
+        .await?;
+    let invitation = invitation.ok_or(Error::NotFound)?;
+    if invitation.accepted.is_some() {
+        return Err(Error::Conflict(String::from(
+            "invitation has already been accepted",
+        )));
+    }
+    let mut invitation: invitations::ActiveModel = invitation.into();
+    let now = DateTime::from(Utc::now());
+    invitation.revoked = Set(Some(now));
+    invitation.updated = Set(now);
+    let invitation = invitation.update(&ctx.db).await?;
+    Ok(invitation)
+}
+
+/// Check an invitation was sent to `user`, whose email must be verified and
+/// match the invited address. Holding the token alone isn't enough.
+fn check_invitee(invitation: &invitations::Model, user: &users::Model) -> Result<(), Error> {
+    let invited = user.email_verified
+        && user
+            .email
+            .as_deref()
+            .is_some_and(|email| email.trim().eq_ignore_ascii_case(&invitation.email));
+    if !invited {
+        return Err(Error::Forbidden);
+    }
+    Ok(())
+}
+
+/// Redeem an invitation token, making `user` a member of the account with
+/// the invited role. Only the invitee can accept, see [`check_invitee`].
+pub async fn accept_invitation(
+    ctx: &Arc<ApiContext>,
+    user: &users::Model,
+    token: &str,
+) -> Result<users_accounts::Model, Error> {
+    let id = ctx.signer.verify(INVITATION_TOKEN, token)?;
+    let id = Uuid::parse_str(&id).map_err(|_| Error::NotFound)?;
+    let txn = ctx.db.begin().await?;
+    let invitation = Invitations::find_by_id(id)
+        .lock_exclusive()
+        .one(&txn)
+        .await?;
+    let invitation = invitation.ok_or(Error::NotFound)?;
+    check_invitee(&invitation, user)?;
+    if invitation.accepted.is_some() || invitation.revoked.is_some() {
+        return Err(Error::Conflict(String::from(
+            "invitation is no longer valid",
+        )));
+    }
+    if invitation.expires < Utc::now() {
+        return Err(Error::Conflict(String::from("invitation has expired")));
+    }
+    let membership = add_member(&txn, invitation.account_id, user.id, invitation.role).await?;
+    let mut invitation: invitations::ActiveModel = invitation.into();
+    let now = DateTime::from(Utc::now());
+    invitation.accepted = Set(Some(now));
+    invitation.accepted_by = Set(Some(user.id));
+    invitation.updated = Set(now);
+    invitation.update(&txn).await?;
+    txn.commit().await?;
+    Ok(membership)
+}
+
+async fn list_invitations_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    access: AccountAccess,
+    page: Pagination,
+) -> Result<impl IntoResponse, Error> {
+    access.require_role(MembershipRole::Admin)?;
+    let invitations = list_invitations(&ctx, access.account_id, &page).await?;
+    Ok(invitations)
+}
+
+async fn create_invitation_handler(
+    user: AuthUser,
+    State(ctx): State<Arc<ApiContext>>,
+    access: AccountAccess,
+    body: Result<Json<CreateInvitation>, JsonRejection>,
+) -> Result<impl IntoResponse, Error> {
+    access.require_role(MembershipRole::Admin)?;
+    let Json(body) = body?;
+    // Members can only hand out roles they hold themselves
+    access.require_role(body.role)?;
+    let invited_by = user.user().ok().map(|user| user.id);
+    let created = create_invitation(&ctx, access.account_id, invited_by, body).await?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+async fn revoke_invitation_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    access: AccountAccess,
+    params: Result<Path<(Uuid, Uuid)>, PathRejection>,
+) -> Result<impl IntoResponse, Error> {
+    access.require_role(MembershipRole::Admin)?;
+    let Path((_, invitation_id)) = params?;
+    let revoked = revoke_invitation(&ctx, access.account_id, invitation_id).await?;
+    Ok(Json(revoked))
+}
+
+async fn accept_invitation_handler(
+    user: AuthUser,
+    State(ctx): State<Arc<ApiContext>>,
+    body: Result<Json<AcceptInvitation>, JsonRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Json(body) = body?;
+    let membership = accept_invitation(&ctx, user.user()?, &body.token).await?;
+    Ok(Json(membership))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invitation(email: &str) -> invitations::Model {
+        let now = Utc::now().into();
+        invitations::Model {
+            id: Uuid::now_v7(),
+            account_id: Uuid::now_v7(),
+            email: email.to_string(),
+            role: MembershipRole::Member,
+            invited_by: None,
+            accepted_by: None,
+            expires: now,
+            accepted: None,
+            revoked: None,
+            created: now,
+            updated: now,
+        }
+    }
+
+    fn user(email: Option<&str>, email_verified: bool) -> users::Model {
+        users::Model {
+            email: email.map(ToString::to_string),
+            email_verified,
+            ..users::Model::for_tests("auth0|1")
+        }
+    }
+
+    #[test]
+    fn test_check_invitee() {
+        let invitation = invitation("jo@example.com");
+        assert!(check_invitee(&invitation, &user(Some("Jo@Example.com"), true)).is_ok());
+    }
+
+    #[test]
+    fn test_check_invitee_mismatch() {
+        let invitation = invitation("jo@example.com");
+        assert!(check_invitee(&invitation, &user(Some("sam@example.com"), true)).is_err());
+        assert!(check_invitee(&invitation, &user(None, true)).is_err());
+    }
+
+    #[test]
+    fn test_check_invitee_unverified() {
+        let invitation = invitation("jo@example.com");
+        assert!(check_invitee(&invitation, &user(Some("jo@example.com"), false)).is_err());
+    }
+}
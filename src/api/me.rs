@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    response::IntoResponse,
+    routing::get,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+use super::{
+    auth::AuthUser,
+    pagination::Pagination,
+    permissions::Routes,
+    users::{list_user_accounts, update_user, UpdateUser},
+    ApiContext,
+};
+
+/// Profile fields users can change about themselves
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateMe {
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    pub name: Option<Option<String>>,
+}
+
+/// Self-service routes for the authenticated user, available without any
+/// permission beyond being signed in
+pub fn routes() -> Routes {
+    Routes::new()
+        .authenticated_route("/v1/me", get(get_me_handler).patch(update_me_handler))
+        .authenticated_route("/v1/me/accounts", get(list_my_accounts_handler))
+}
+
+async fn get_me_handler(user: AuthUser) -> Result<impl IntoResponse, Error> {
+    Ok(Json(user.user()?.clone()))
+}
+
+async fn update_me_handler(
+    user: AuthUser,
+    State(ctx): State<Arc<ApiContext>>,
+    body: Result<Json<UpdateMe>, JsonRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Json(body) = body?;
+    let update = UpdateUser {
+        provider_id: None,
+        stripe_customer_id: None,
+        name: body.name,
+    };
+    let updated = update_user(&ctx, user.user()?.id, update).await?;
+    Ok(Json(updated))
+}
+
+async fn list_my_accounts_handler(
+    user: AuthUser,
+    State(ctx): State<Arc<ApiContext>>,
+    page: Pagination,
+) -> Result<impl IntoResponse, Error> {
+    let accounts = list_user_accounts(&ctx, user.user()?.id, &page, false).await?;
+    Ok(accounts)
+}
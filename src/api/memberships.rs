@@ -0,0 +1,356 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{
+        rejection::{JsonRejection, PathRejection},
+        FromRef, FromRequestParts, Path, State,
+    },
+    http::{request::Parts, StatusCode},
+    response::IntoResponse,
+    routing::{delete, patch, post},
+    Json, RequestPartsExt,
+};
+use chrono::{DateTime, Utc};
+use sea_orm::{entity::*, query::*, DatabaseTransaction};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::entity::{
+    prelude::*,
+    soft_delete::SoftDeleteFilter,
+    users_accounts::{self, MembershipRole},
+};
+use crate::error::Error;
+
+use super::{
+    auth::AuthUser,
+    permissions::{Permission, Routes},
+    ApiContext,
+};
+
+/// Global permission that grants access to every account regardless of
+/// membership
+pub const ACCOUNT_ADMIN: &str = "admin:account";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateMembership {
+    pub user_id: Uuid,
+    pub role: MembershipRole,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateMembership {
+    pub role: MembershipRole,
+}
+
+pub fn routes() -> Routes {
+    Routes::new()
+        .route_with_permission(
+            "/v1/accounts/:id/users",
+            post(create_membership_handler),
+            Permission::AllOf(&["create:user:account"]),
+        )
+        .route_with_permission(
+            "/v1/accounts/:id/users/:user_id",
+            patch(update_membership_handler),
+            Permission::AllOf(&["update:user:account"]),
+        )
+        .route_with_permission(
+            "/v1/accounts/:id/users/:user_id",
+            delete(delete_membership_handler),
+            Permission::AllOf(&["delete:user:account"]),
+        )
+}
+
+/// The caller's access to the account identified by the `:id` path parameter.
+///
+/// Extracting this rejects callers that are not a member of the account,
+/// unless they hold [`ACCOUNT_ADMIN`], before the handler runs. Non-members
+/// get a 404 so account ids can't be probed. The result is kept in the
+/// request extensions, so the rate limiter and the handler share one lookup.
+#[derive(Debug, Clone)]
+pub struct AccountAccess {
+    pub account_id: Uuid,
+    /// The caller's membership, `None` when access comes from [`ACCOUNT_ADMIN`]
+    pub membership: Option<users_accounts::Model>,
+}
+
+impl AccountAccess {
+    /// Whether access was granted by the global admin permission
+    pub fn is_admin(&self) -> bool {
+        self.membership.is_none()
+    }
+
+    /// Require the caller's membership role to satisfy `role`,
+    /// global admins always pass
+    pub fn require_role(&self, role: MembershipRole) -> Result<(), Error> {
+        match &self.membership {
+            Some(membership) if !membership.role.satisfies(role) => Err(Error::Forbidden),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AccountAccess
+where
+    Arc<ApiContext>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(access) = parts.extensions.get::<Self>() {
+            return Ok(access.clone());
+        }
+        let ctx = Arc::from_ref(state);
+        let user = AuthUser::from_request_parts(parts, state).await?;
+        let Path(params) = parts.extract::<Path<HashMap<String, Uuid>>>().await?;
+        let account_id = *params.get("id").ok_or(Error::NotFound)?;
+        let access = if user.has_permission(ACCOUNT_ADMIN).is_ok() {
+            Self {
+                account_id,
+                membership: None,
+            }
+        } else {
+            let membership = get_membership(&ctx, account_id, user.user()?.id)
+                .await?
+                .ok_or(Error::NotFound)?;
+            Self {
+                account_id,
+                membership: Some(membership),
+            }
+        };
+        parts.extensions.insert(access.clone());
+        Ok(access)
+    }
+}
+
+/// Fetch an active membership of a user in an account
+pub async fn get_membership(
+    ctx: &ApiContext,
+    account_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<users_accounts::Model>, Error> {
+    let membership = UsersAccounts::find()
+        .filter(users_accounts::Column::AccountId.eq(account_id))
+        .filter(users_accounts::Column::UserId.eq(user_id))
+        .active::<UsersAccounts>()
+        .one(&ctx.db)
+        .await?;
+    Ok(membership)
+}
+
+/// Add a user to an account, restoring a previously removed membership
+pub async fn add_member(
+    txn: &DatabaseTransaction,
+    account_id: Uuid,
+    user_id: Uuid,
+    role: MembershipRole,
+) -> Result<users_accounts::Model, Error> {
+    let existing = UsersAccounts::find_by_id((user_id, account_id))
+        .lock_exclusive()
+        .one(txn)
+        .await?;
+    match existing {
+        Some(membership) if membership.deleted.is_none() => Err(Error::Conflict(String::from(
+            "user is already a member of the account",
+        ))),
+        Some(membership) => {
+            let mut membership: users_accounts::ActiveModel = membership.into();
+            membership.role = Set(role);
+            membership.deleted = Set(None);
+            membership.updated = Set(DateTime::from(Utc::now()));
+            Ok(membership.update(txn).await?)
+        }
+        None => {
+            let membership = users_accounts::ActiveModel {
+                user_id: Set(user_id),
+                account_id: Set(account_id),
+                role: Set(role),
+                ..Default::default()
+            };
+            Ok(membership.insert(txn).await?)
+        }
+    }
+}
+
+/// Lock an account for the rest of the transaction so concurrent membership
+/// changes can't race past the owner check
+async fn lock_account(txn: &DatabaseTransaction, account_id: Uuid) -> Result<(), Error> {
+    Accounts::find_by_id(account_id)
+        .active::<Accounts>()
+        .lock_exclusive()
+        .one(txn)
+        .await?
+        .ok_or(Error::NotFound)?;
+    Ok(())
+}
+
+/// Fail if `user_id` losing ownership would leave the account without an owner
+async fn ensure_other_owner(
+    txn: &DatabaseTransaction,
+    account_id: Uuid,
+    user_id: Uuid,
+) -> Result<(), Error> {
+    let owners = UsersAccounts::find()
+        .filter(users_accounts::Column::AccountId.eq(account_id))
+        .filter(users_accounts::Column::UserId.ne(user_id))
+        .filter(users_accounts::Column::Role.eq(MembershipRole::Owner))
+        .active::<UsersAccounts>()
+        .count(txn)
+        .await?;
+    if owners == 0 {
+        return Err(Error::Conflict(String::from(
+            "an account must keep at least one owner",
+        )));
+    }
+    Ok(())
+}
+
+/// Fail if removing `user_id` entirely would leave any active account they
+/// own without an owner
+pub async fn ensure_not_sole_owner(txn: &DatabaseTransaction, user_id: Uuid) -> Result<(), Error> {
+    let owned = UsersAccounts::find()
+        .join(
+            JoinType::InnerJoin,
+            users_accounts::Relation::Accounts.def(),
+        )
+        .filter(users_accounts::Column::UserId.eq(user_id))
+        .filter(users_accounts::Column::Role.eq(MembershipRole::Owner))
+        .active::<UsersAccounts>()
+        .active::<Accounts>()
+        .all(txn)
+        .await?;
+    for membership in owned {
+        lock_account(txn, membership.account_id).await?;
+        ensure_other_owner(txn, membership.account_id, user_id).await?;
+    }
+    Ok(())
+}
+
+/// Fetch an active membership within a transaction, locking it
+async fn find_membership_for_update(
+    txn: &DatabaseTransaction,
+    account_id: Uuid,
+    user_id: Uuid,
+) -> Result<users_accounts::Model, Error> {
+    UsersAccounts::find_by_id((user_id, account_id))
+        .active::<UsersAccounts>()
+        .lock_exclusive()
+        .one(txn)
+        .await?
+        .ok_or(Error::NotFound)
+}
+
+pub async fn create_membership(
+    ctx: &Arc<ApiContext>,
+    account_id: Uuid,
+    membership: CreateMembership,
+) -> Result<users_accounts::Model, Error> {
+    let txn = ctx.db.begin().await?;
+    Users::find_by_id(membership.user_id)
+        .active::<Users>()
+        .one(&txn)
+        .await?
+        .ok_or(Error::NotFound)?;
+    let membership = add_member(&txn, account_id, membership.user_id, membership.role).await?;
+    txn.commit().await?;
+    Ok(membership)
+}
+
+/// Change a member's role, an account must always keep an owner
+pub async fn update_membership(
+    ctx: &Arc<ApiContext>,
+    account_id: Uuid,
+    user_id: Uuid,
+    role: MembershipRole,
+) -> Result<users_accounts::Model, Error> {
+    let txn = ctx.db.begin().await?;
+    lock_account(&txn, account_id).await?;
+    let membership = find_membership_for_update(&txn, account_id, user_id).await?;
+    if membership.role == MembershipRole::Owner && role != MembershipRole::Owner {
+        ensure_other_owner(&txn, account_id, user_id).await?;
+    }
+    let mut membership: users_accounts::ActiveModel = membership.into();
+    membership.role = Set(role);
+    membership.updated = Set(DateTime::from(Utc::now()));
+    let membership = membership.update(&txn).await?;
+    txn.commit().await?;
+    Ok(membership)
+}
+
+/// Soft delete a membership, an account must always keep an owner
+pub async fn delete_membership(
+    ctx: &Arc<ApiContext>,
+    account_id: Uuid,
+    user_id: Uuid,
+) -> Result<users_accounts::Model, Error> {
+    let txn = ctx.db.begin().await?;
+    lock_account(&txn, account_id).await?;
+    let membership = find_membership_for_update(&txn, account_id, user_id).await?;
+    if membership.role == MembershipRole::Owner {
+        ensure_other_owner(&txn, account_id, user_id).await?;
+    }
+    let mut membership: users_accounts::ActiveModel = membership.into();
+    let now = DateTime::from(Utc::now());
+    membership.deleted = Set(Some(now));
+    membership.updated = Set(now);
+    let membership = membership.update(&txn).await?;
+    txn.commit().await?;
+    Ok(membership)
+}
+
+async fn create_membership_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    access: AccountAccess,
+    body: Result<Json<CreateMembership>, JsonRejection>,
+) -> Result<impl IntoResponse, Error> {
+    access.require_role(MembershipRole::Admin)?;
+    let Json(body) = body?;
+    // Members can only hand out roles they hold themselves
+    access.require_role(body.role)?;
+    let created = create_membership(&ctx, access.account_id, body).await?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+async fn update_membership_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    access: AccountAccess,
+    params: Result<Path<(Uuid, Uuid)>, PathRejection>,
+    body: Result<Json<UpdateMembership>, JsonRejection>,
+) -> Result<impl IntoResponse, Error> {
+    access.require_role(MembershipRole::Admin)?;
+    let Path((_, user_id)) = params?;
+    let Json(body) = body?;
+    access.require_role(body.role)?;
+    let target = get_membership(&ctx, access.account_id, user_id)
+        .await?
+        .ok_or(Error::NotFound)?;
+    // Only owners can change another owner's role
+    access.require_role(target.role)?;
+    let updated = update_membership(&ctx, access.account_id, user_id, body.role).await?;
+    Ok(Json(updated))
+}
+
+async fn delete_membership_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    access: AccountAccess,
+    params: Result<Path<(Uuid, Uuid)>, PathRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Path((_, user_id)) = params?;
+    // Members can always leave an account themselves
+    let leaving = access
+        .membership
+        .as_ref()
+        .is_some_and(|membership| membership.user_id == user_id);
+    if !leaving {
+        access.require_role(MembershipRole::Admin)?;
+        let target = get_membership(&ctx, access.account_id, user_id)
+            .await?
+            .ok_or(Error::NotFound)?;
+        access.require_role(target.role)?;
+    }
+    let deleted = delete_membership(&ctx, access.account_id, user_id).await?;
+    Ok(Json(deleted))
+}
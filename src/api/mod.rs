@@ -2,15 +2,22 @@
 //! Axum app from a Config and attempts to serve it
 
 use crate::auth0::Client;
-use crate::config::Config;
-use crate::token_bucket::TokenBucket;
+use crate::config::{Config, RateLimitBackend};
+use crate::dev_issuer::DevIssuer;
+use crate::rate_limit::{MemoryStore, PostgresStore, RateLimitStore};
+use crate::revocation::RevocationList;
+use crate::signed_token::TokenSigner;
+use crate::user_cache::UserCache;
 use ::stripe::Client as StripeClient;
 use ::stripe::RequestStrategy::ExponentialBackoff;
 use anyhow::Result;
-use axum::{middleware, Router};
-use dashmap::DashMap;
+use axum::http::{Method, StatusCode};
+use axum::middleware;
+use axum::Router;
+use permissions::Routes;
 use sea_orm::{ConnectOptions, Database, DatabaseConnection};
-use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::{select, signal};
 use tower_http::compression::CompressionLayer;
@@ -18,22 +25,43 @@ use tower_http::cors::CorsLayer;
 use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::TraceLayer;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
 mod accounts;
 mod auth;
+mod erasure;
+mod expand;
+mod exports;
+mod filters;
+mod invitations;
+mod me;
+mod memberships;
 mod pagination;
+mod permissions;
+mod profiles;
 mod public;
 mod ratelimit;
+mod revocations;
+mod scope;
+mod search;
 mod stripe;
+mod tasks;
 mod users;
+mod validation;
 
 pub struct ApiContext {
     db: DatabaseConnection,
     config: Config,
-    rate_limit: DashMap<IpAddr, TokenBucket>,
+    rate_limit: Box<dyn RateLimitStore>,
     stripe_client: StripeClient,
     auth0_client: Client,
+    revocations: RevocationList,
+    users: UserCache,
+    dev_issuer: Option<DevIssuer>,
+    signer: TokenSigner,
+    // Methods registered without a permission by path, these skip
+    // authentication
+    public_routes: HashMap<&'static str, Vec<Method>>,
 }
 
 /// Creates a signal handler for graceful shutdown.
@@ -70,6 +98,23 @@ async fn shutdown_signal(ctx: Arc<ApiContext>) {
     info!("Graceful shutdown complete");
 }
 
+/// All API routes along with the permissions required to call them
+fn routes() -> Routes {
+    Routes::new()
+        .merge(public::routes())
+        .merge(accounts::routes())
+        .merge(invitations::routes())
+        .merge(memberships::routes())
+        .merge(users::routes())
+        .merge(me::routes())
+        .merge(search::routes())
+        .merge(erasure::routes())
+        .merge(exports::routes())
+        .merge(revocations::routes())
+        .merge(stripe::routes())
+        .merge(profiles::routes())
+}
+
 /// Create and serve an Axum server with pre-registered routes
 /// and middleware
 pub async fn serve(config: Config) -> Result<()> {
@@ -81,6 +126,15 @@ pub async fn serve(config: Config) -> Result<()> {
 
     let db = Database::connect(opts).await?;
 
+    let rate_limit: Box<dyn RateLimitStore> = match config.rate_limit_store {
+        RateLimitBackend::Memory => Box::new(MemoryStore::new()),
+        RateLimitBackend::Postgres => Box::new(PostgresStore::new(
+            db.clone(),
+            config.rate_limit_batch,
+            config.rate_limit_lease,
+        )),
+    };
+
     let stripe_client =
         StripeClient::new(&config.stripe_secret_key).with_strategy(ExponentialBackoff(5));
 
@@ -89,37 +143,151 @@ pub async fn serve(config: Config) -> Result<()> {
         config.auth0_client_id.clone(),
         config.auth0_client_secret.clone(),
     );
-    auth0_client.load_jwk().await?;
+    if let Some(jwks_url) = &config.auth0_jwks_url {
+        auth0_client = auth0_client.with_jwks_url(jwks_url.clone());
+    }
+    if let Some(audience) = &config.auth0_audience {
+        auth0_client = auth0_client.with_audience(audience.clone());
+    }
+
+    let dev_issuer = if config.dev_issuer {
+        warn!("Development token issuer is enabled");
+        let issuer = DevIssuer::load_or_generate(&config.dev_issuer_key_path)?;
+        auth0_client.add_jwks(issuer.jwks())?;
+        Some(issuer)
+    } else {
+        None
+    };
+
+    // The dev issuer can stand in for Auth0 entirely
+    if dev_issuer.is_none() || !config.auth0_domain.is_empty() || config.auth0_jwks_url.is_some() {
+        auth0_client.load_jwk().await?;
+    }
+
+    let signer = if config.signing_secret.is_empty() {
+        warn!("No signing secret configured, issued links won't survive a restart");
+        TokenSigner::new(&rand::random::<[u8; 32]>())
+    } else {
+        TokenSigner::new(config.signing_secret.as_bytes())
+    };
+
+    let routes = routes();
+    for route in routes.info() {
+        match route.permission {
+            Some(permission) => debug!("Route {} requires {:?}", route.path, permission),
+            None => debug!("Route {} is public", route.path),
+        }
+    }
+    let public_routes = routes.public_methods();
 
     let state = Arc::new(ApiContext {
         config: config.clone(),
         db,
-        rate_limit: DashMap::new(),
+        rate_limit,
         stripe_client,
         auth0_client,
+        revocations: RevocationList::new(),
+        users: UserCache::new(config.user_cache_ttl),
+        dev_issuer,
+        signer,
+        public_routes,
     });
 
-    let app = Router::new()
-        .merge(public::routes())
-        .merge(accounts::routes())
-        .merge(users::routes())
-        .merge(stripe::routes())
-        .layer(TimeoutLayer::new(config.request_timeout))
-        .layer(TraceLayer::new_for_http())
-        .layer(CompressionLayer::new())
+    revocations::refresh_revocations(&state).await?;
+    let refresh_ctx = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(refresh_ctx.config.revocation_refresh_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = revocations::refresh_revocations(&refresh_ctx).await {
+                error!("Failed to refresh revocation list: {:?}", e);
+            }
+        }
+    });
+
+    let purge_ctx = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(purge_ctx.config.purge_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = erasure::purge_deleted_users(&purge_ctx).await {
+                error!("Failed to purge deleted users: {:?}", e);
+            }
+            if let Err(e) = exports::expire_exports(&purge_ctx).await {
+                error!("Failed to expire exports: {:?}", e);
+            }
+            if let Err(e) = purge_ctx.rate_limit.purge().await {
+                error!("Failed to purge rate limits: {:?}", e);
+            }
+        }
+    });
+
+    let worker_ctx = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(worker_ctx.config.task_poll_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = tasks::work(&worker_ctx).await {
+                error!("Failed to run background tasks: {:?}", e);
+            }
+        }
+    });
+
+    let app = app(routes, state.clone());
+
+    info!("Listening on {}", addr);
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal(state.clone()))
+        .await?;
+    Ok(())
+}
+
+/// The router for a set of routes with every middleware layer applied
+fn app(routes: Routes, state: Arc<ApiContext>) -> Router {
+    let request_timeout = state.config.request_timeout;
+    routes
+        .into_router()
         .layer(middleware::from_fn_with_state(
             state.clone(),
             ratelimit::limiter,
         ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            ratelimit::ip_limiter,
+        ))
+        .layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            request_timeout,
+        ))
+        .layer(TraceLayer::new_for_http())
+        .layer(CompressionLayer::new())
         .layer(CorsLayer::new())
         .layer(PropagateRequestIdLayer::x_request_id())
         .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
-        .with_state(state.clone());
+        .with_state(state)
+}
 
-    info!("Listening on {}", addr);
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal(state.clone()))
-        .await?;
-    Ok(())
+#[cfg(test)]
+impl ApiContext {
+    /// A context without a database or upstream services, enough to exercise
+    /// middleware and routes that don't reach them
+    fn for_tests(config: Config) -> Self {
+        Self {
+            db: DatabaseConnection::default(),
+            rate_limit: Box::new(MemoryStore::new()),
+            stripe_client: StripeClient::new(&config.stripe_secret_key),
+            auth0_client: Client::new(String::new(), String::new(), String::new()),
+            revocations: RevocationList::new(),
+            users: UserCache::new(config.user_cache_ttl),
+            dev_issuer: None,
+            signer: TokenSigner::new(b"test"),
+            public_routes: routes().public_methods(),
+            config,
+        }
+    }
 }
@@ -3,17 +3,75 @@ use std::collections::HashMap;
 use axum::{
     async_trait,
     extract::{FromRequestParts, Query},
-    http::request::Parts,
-    RequestPartsExt,
+    http::{header, request::Parts, HeaderValue, Uri},
+    response::{IntoResponse, Response},
+    Json, RequestPartsExt,
 };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::DateTime;
+use sea_orm::{
+    ColumnTrait, ColumnType, Condition, ConnectionTrait, EntityTrait, ModelTrait, Order,
+    PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, QueryTrait, Select, Statement, Value,
+};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::error::Error;
 
+use super::ApiContext;
+
+/// Page size used when `limit` isn't given
+const DEFAULT_LIMIT: u64 = 10;
+
+/// Largest page size a client can ask for
+const MAX_LIMIT: u64 = 100;
+
+/// Position in a listing, handed to clients as an opaque base64 string so
+/// its contents can change without breaking them. Holds the sort the
+/// listing was walked in and the sort keys of a row, id last.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    keys: Vec<serde_json::Value>,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursor serializes");
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    fn decode(cursor: &str) -> Result<Self, Error> {
+        let json = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|_| invalid_cursor())?;
+        serde_json::from_slice(&json).map_err(|_| invalid_cursor())
+    }
+}
+
+fn invalid_cursor() -> Error {
+    Error::BadRequest(String::from("cursor is invalid"))
+}
+
+/// Which side of the cursor a page is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    After,
+    Before,
+}
+
+/// Keyset pagination parameters, `?limit=10&sort=-created,name&cursor=...`.
+/// Rows strictly after `cursor`, or before `before`, are returned so pages
+/// never overlap. `include_total=true` also counts the rows matched.
 #[derive(Debug, Clone)]
 pub struct Pagination {
     pub limit: u64,
-    pub after: Uuid,
+    /// Requested sort fields, with whether they're descending
+    sort: Vec<(String, bool)>,
+    cursor: Option<(Direction, String)>,
+    include_total: bool,
+    /// The request uri, used to link to neighbouring pages
+    uri: Uri,
 }
 
 #[async_trait]
@@ -27,12 +85,626 @@ where
         let limit = params
             .get("limit")
             .and_then(|s| s.parse::<u64>().ok())
-            .unwrap_or(10)
-            .min(100);
-        let after = params
-            .get("after")
-            .and_then(|s| s.parse::<Uuid>().ok())
-            .unwrap_or(Uuid::nil());
-        Ok(Pagination { limit, after })
+            .unwrap_or(DEFAULT_LIMIT)
+            .clamp(1, MAX_LIMIT);
+        let sort = params
+            .get("sort")
+            .map(|sort| parse_sort(sort))
+            .transpose()?
+            .unwrap_or_default();
+        let cursor = match (params.get("cursor"), params.get("before")) {
+            (Some(_), Some(_)) => {
+                return Err(Error::BadRequest(String::from(
+                    "cursor and before can't be used together",
+                )))
+            }
+            (Some(cursor), None) => Some((Direction::After, cursor.clone())),
+            (None, Some(before)) => Some((Direction::Before, before.clone())),
+            (None, None) => None,
+        };
+        let include_total = match params.get("include_total") {
+            Some(value) => value.parse::<bool>().map_err(|_| {
+                Error::BadRequest(String::from("include_total must be true or false"))
+            })?,
+            None => false,
+        };
+        Ok(Pagination {
+            limit,
+            sort,
+            cursor,
+            include_total,
+            uri: parts.uri.clone(),
+        })
+    }
+}
+
+/// Parse a comma separated list of fields, each prefixed with `-` to sort
+/// it in descending order
+fn parse_sort(sort: &str) -> Result<Vec<(String, bool)>, Error> {
+    let mut fields: Vec<(String, bool)> = Vec::new();
+    for field in sort.split(',').filter(|field| !field.is_empty()) {
+        let (name, descending) = match field.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (field, false),
+        };
+        if fields.iter().any(|(existing, _)| existing == name) {
+            return Err(Error::BadRequest(format!(
+                "`{name}` is sorted more than once"
+            )));
+        }
+        fields.push((name.to_string(), descending));
+    }
+    Ok(fields)
+}
+
+impl Pagination {
+    /// Resolve the requested sort against the fields a listing allows, `id`
+    /// always being sortable and breaking ties between rows
+    pub fn sort<C: ColumnTrait>(
+        &self,
+        id: C,
+        sortable: &[(&str, C)],
+    ) -> Result<Sort<'_, C>, Error> {
+        let mut keys = Vec::with_capacity(self.sort.len() + 1);
+        for (field, descending) in &self.sort {
+            let column = match field.as_str() {
+                "id" => id,
+                _ => sortable
+                    .iter()
+                    .find(|(name, _)| name == field)
+                    .map(|(_, column)| *column)
+                    .ok_or_else(|| Error::BadRequest(format!("can't sort by `{field}`")))?,
+            };
+            keys.push((column, *descending));
+        }
+        if !self.sort.iter().any(|(field, _)| field == "id") {
+            keys.push((id, false));
+        }
+        let values = match &self.cursor {
+            Some((_, cursor)) => {
+                let cursor = Cursor::decode(cursor)?;
+                if cursor.sort != self.sort_param() {
+                    return Err(Error::BadRequest(String::from(
+                        "cursor was created with a different sort",
+                    )));
+                }
+                if cursor.keys.len() != keys.len() {
+                    return Err(invalid_cursor());
+                }
+                let values = keys
+                    .iter()
+                    .zip(cursor.keys)
+                    .map(|((column, _), key)| from_json(*column, key))
+                    .collect::<Result<_, _>>()?;
+                Some(values)
+            }
+            None => None,
+        };
+        Ok(Sort {
+            page: self,
+            keys,
+            values,
+        })
+    }
+
+    /// The requested sort in its canonical form
+    fn sort_param(&self) -> String {
+        self.sort
+            .iter()
+            .map(|(field, descending)| {
+                if *descending {
+                    format!("-{field}")
+                } else {
+                    field.clone()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn direction(&self) -> Option<Direction> {
+        self.cursor.as_ref().map(|(direction, _)| *direction)
+    }
+}
+
+/// A resolved sort, used to query a page with [`Keyset`] and then to build
+/// the page from the rows fetched
+#[derive(Debug)]
+pub struct Sort<'a, C> {
+    page: &'a Pagination,
+    /// Sort columns with whether they're descending, ending with the id
+    keys: Vec<(C, bool)>,
+    /// Sort key values of the cursor row
+    values: Option<Vec<Value>>,
+}
+
+impl<C: ColumnTrait> Sort<'_, C> {
+    fn backward(&self) -> bool {
+        self.page.direction() == Some(Direction::Before)
+    }
+
+    /// Rows past the cursor: those with a greater first key, or an equal
+    /// first key and a greater second key, and so on
+    fn condition(&self, values: &[Value]) -> Condition {
+        let backward = self.backward();
+        let mut condition = Condition::any();
+        for (i, (column, descending)) in self.keys.iter().enumerate() {
+            let mut tuple = Condition::all();
+            for ((column, _), value) in self.keys[..i].iter().zip(values) {
+                tuple = tuple.add(column.eq(value.clone()));
+            }
+            let value = values[i].clone();
+            tuple = tuple.add(if descending ^ backward {
+                column.lt(value)
+            } else {
+                column.gt(value)
+            });
+            condition = condition.add(tuple);
+        }
+        condition
+    }
+
+    /// Fetch a page of `query`. When a total was asked for the rows matched
+    /// are counted at the same time, on a separate connection.
+    pub async fn fetch<E>(
+        &self,
+        ctx: &ApiContext,
+        query: Select<E>,
+    ) -> Result<Page<E::Model>, Error>
+    where
+        E: EntityTrait<Column = C>,
+        E::Model: Sync,
+    {
+        let rows = async { Ok(query.clone().keyset(self).all(&ctx.db).await?) };
+        let total = async {
+            if self.page.include_total {
+                count(ctx, query.clone()).await.map(Some)
+            } else {
+                Ok(None)
+            }
+        };
+        let (rows, total) = tokio::try_join!(rows, total)?;
+        let mut page = self.page(rows);
+        page.total = total;
+        Ok(page)
+    }
+
+    /// Build a page from rows fetched by a query using [`Keyset`]
+    pub fn page<M>(&self, mut rows: Vec<M>) -> Page<M>
+    where
+        M: ModelTrait,
+        M::Entity: EntityTrait<Column = C>,
+    {
+        let has_more = rows.len() as u64 > self.page.limit;
+        rows.truncate(usize::try_from(self.page.limit).unwrap_or(usize::MAX));
+        if self.backward() {
+            rows.reverse();
+        }
+        let (more_after, more_before) = match self.page.direction() {
+            None => (has_more, false),
+            Some(Direction::After) => (has_more, true),
+            Some(Direction::Before) => (true, has_more),
+        };
+        let next_cursor = rows
+            .last()
+            .filter(|_| more_after)
+            .map(|row| self.cursor(row));
+        let prev_cursor = rows
+            .first()
+            .filter(|_| more_before)
+            .map(|row| self.cursor(row));
+        let mut links = Vec::new();
+        if let Some(cursor) = &next_cursor {
+            links.push(link(&self.page.uri, "cursor", cursor, "next"));
+        }
+        if let Some(cursor) = &prev_cursor {
+            links.push(link(&self.page.uri, "before", cursor, "prev"));
+        }
+        Page {
+            data: rows,
+            next_cursor,
+            prev_cursor,
+            has_more,
+            total: None,
+            links,
+        }
+    }
+
+    fn cursor<M>(&self, row: &M) -> String
+    where
+        M: ModelTrait,
+        M::Entity: EntityTrait<Column = C>,
+    {
+        let keys = self
+            .keys
+            .iter()
+            .map(|(column, _)| to_json(row.get(*column)))
+            .collect();
+        Cursor {
+            sort: self.page.sort_param(),
+            keys,
+        }
+        .encode()
+    }
+}
+
+/// Only non-null columns of these types are sortable, the keyset predicates
+/// don't account for nulls
+fn to_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Bool(Some(v)) => v.into(),
+        Value::Int(Some(v)) => v.into(),
+        Value::BigInt(Some(v)) => v.into(),
+        Value::String(Some(v)) => (*v).into(),
+        Value::Uuid(Some(v)) => v.to_string().into(),
+        Value::ChronoDateTimeWithTimeZone(Some(v)) => v.to_rfc3339().into(),
+        _ => serde_json::Value::Null,
+    }
+}
+
+fn from_json<C: ColumnTrait>(column: C, key: serde_json::Value) -> Result<Value, Error> {
+    let value = match column.def().get_column_type() {
+        ColumnType::Boolean => key.as_bool().map(Value::from),
+        ColumnType::Integer => key
+            .as_i64()
+            .and_then(|v| i32::try_from(v).ok())
+            .map(Value::from),
+        ColumnType::BigInteger => key.as_i64().map(Value::from),
+        ColumnType::String(_) | ColumnType::Text => key.as_str().map(Value::from),
+        ColumnType::Uuid => key
+            .as_str()
+            .and_then(|v| v.parse::<Uuid>().ok())
+            .map(Value::from),
+        ColumnType::TimestampWithTimeZone => key
+            .as_str()
+            .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+            .map(Value::from),
+        _ => None,
+    };
+    value.ok_or_else(invalid_cursor)
+}
+
+/// Applies keyset pagination to a query, ordering by the sort keys and
+/// fetching one row more than the page size to tell whether another page
+/// follows. Pages before a cursor are fetched in reverse.
+pub trait Keyset: QueryFilter + QueryOrder + QuerySelect + Sized {
+    fn keyset<C: ColumnTrait>(self, sort: &Sort<'_, C>) -> Self {
+        let mut query = self;
+        if let Some(values) = &sort.values {
+            query = query.filter(sort.condition(values));
+        }
+        for (column, descending) in &sort.keys {
+            let order = if descending ^ sort.backward() {
+                Order::Desc
+            } else {
+                Order::Asc
+            };
+            query = query.order_by(*column, order);
+        }
+        query.limit(sort.page.limit + 1)
+    }
+}
+
+impl<Q: QueryFilter + QueryOrder + QuerySelect> Keyset for Q {}
+
+/// A page of a listing. Serialized as an envelope, with neighbouring pages
+/// also linked through an RFC 8288 `Link` header. `has_more` tells whether
+/// more rows follow in the direction the listing is being walked.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub data: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub prev_cursor: Option<String>,
+    pub has_more: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<Total>,
+    #[serde(skip)]
+    links: Vec<String>,
+}
+
+impl<T> Page<T> {
+    /// Replace the rows of a page, keeping its cursors and total
+    pub fn with_data<U>(self, data: Vec<U>) -> Page<U> {
+        Page {
+            data,
+            next_cursor: self.next_cursor,
+            prev_cursor: self.prev_cursor,
+            has_more: self.has_more,
+            total: self.total,
+            links: self.links,
+        }
+    }
+}
+
+/// Number of rows a listing matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Total {
+    pub count: u64,
+    /// Whether every row was counted, rather than estimated by the planner
+    pub exact: bool,
+}
+
+/// Count the rows `query` matches. The planner's estimate is taken first,
+/// and rows are only counted exactly when it's below the configured
+/// threshold, since counting scans every row.
+async fn count<E>(ctx: &ApiContext, query: Select<E>) -> Result<Total, Error>
+where
+    E: EntityTrait,
+    E::Model: Sync,
+{
+    let estimate = estimate(&ctx.db, &query).await?;
+    if estimate >= ctx.config.count_estimate_threshold {
+        return Ok(Total {
+            count: estimate,
+            exact: false,
+        });
+    }
+    let count = query.count(&ctx.db).await?;
+    Ok(Total { count, exact: true })
+}
+
+/// The number of rows the planner expects `query` to return
+async fn estimate<E: EntityTrait>(
+    db: &impl ConnectionTrait,
+    query: &Select<E>,
+) -> Result<u64, Error> {
+    let backend = db.get_database_backend();
+    let statement = query.clone().build(backend);
+    let explain = Statement::from_sql_and_values(
+        backend,
+        format!("EXPLAIN (FORMAT JSON) {}", statement.sql),
+        statement.values.map(|values| values.0).unwrap_or_default(),
+    );
+    let plan = db
+        .query_one(explain)
+        .await?
+        .map(|row| row.try_get::<serde_json::Value>("", "QUERY PLAN"))
+        .transpose()?;
+    plan.as_ref()
+        .and_then(plan_rows)
+        .ok_or_else(|| anyhow::anyhow!("Query plan has no row estimate").into())
+}
+
+/// Rows estimated by the top node of an `EXPLAIN (FORMAT JSON)` plan
+fn plan_rows(plan: &serde_json::Value) -> Option<u64> {
+    let rows = plan.get(0)?.get("Plan")?.get("Plan Rows")?.as_f64()?;
+    Some(rows.max(0.0).round() as u64)
+}
+
+/// A link to the request uri with its cursors replaced by `param=cursor`
+fn link(uri: &Uri, param: &str, cursor: &str, rel: &str) -> String {
+    let mut query: Vec<&str> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| {
+            !pair.is_empty() && !pair.starts_with("cursor=") && !pair.starts_with("before=")
+        })
+        .collect();
+    let cursor = format!("{param}={cursor}");
+    query.push(&cursor);
+    format!("<{}?{}>; rel=\"{rel}\"", uri.path(), query.join("&"))
+}
+
+impl<T: Serialize> IntoResponse for Page<T> {
+    fn into_response(self) -> Response {
+        let link = if self.links.is_empty() {
+            None
+        } else {
+            HeaderValue::from_str(&self.links.join(", ")).ok()
+        };
+        let mut response = Json(self).into_response();
+        if let Some(link) = link {
+            response.headers_mut().insert(header::LINK, link);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use sea_orm::{DbBackend, QueryTrait};
+
+    use super::*;
+    use crate::entity::{accounts, prelude::*};
+
+    const SORTABLE: &[(&str, accounts::Column)] = &[
+        ("name", accounts::Column::Name),
+        ("created", accounts::Column::Created),
+    ];
+
+    fn pagination(uri: &str, limit: u64, sort: &str) -> Pagination {
+        Pagination {
+            limit,
+            sort: parse_sort(sort).unwrap(),
+            cursor: None,
+            include_total: false,
+            uri: uri.parse().unwrap(),
+        }
+    }
+
+    fn account(name: &str) -> accounts::Model {
+        let now = Utc::now().into();
+        accounts::Model {
+            id: Uuid::now_v7(),
+            name: name.to_string(),
+            status: accounts::AccountStatus::Active,
+            created: now,
+            updated: now,
+            deleted: None,
+        }
+    }
+
+    fn accounts(count: usize) -> Vec<accounts::Model> {
+        (0..count).map(|i| account(&i.to_string())).collect()
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = Cursor {
+            sort: String::from("-created"),
+            keys: vec![Uuid::now_v7().to_string().into()],
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+    }
+
+    #[test]
+    fn test_invalid_cursor() {
+        assert!(Cursor::decode("not a cursor").is_err());
+        assert!(Cursor::decode(&URL_SAFE_NO_PAD.encode("{}")).is_err());
+    }
+
+    #[test]
+    fn test_parse_sort() {
+        assert_eq!(
+            parse_sort("-created,name").unwrap(),
+            vec![
+                (String::from("created"), true),
+                (String::from("name"), false)
+            ]
+        );
+        assert!(parse_sort("name,-name").is_err());
+    }
+
+    #[test]
+    fn test_unsortable_field() {
+        let page = pagination("/v1/accounts", 10, "status");
+        assert!(page.sort(accounts::Column::Id, SORTABLE).is_err());
+    }
+
+    #[test]
+    fn test_last_page() {
+        let page = pagination("/v1/accounts", 3, "");
+        let sort = page.sort(accounts::Column::Id, &[]).unwrap();
+        let page = sort.page(accounts(3));
+        assert_eq!(page.data.len(), 3);
+        assert!(!page.has_more);
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(page.prev_cursor, None);
+        assert!(page.links.is_empty());
+    }
+
+    #[test]
+    fn test_next_page() {
+        let rows = accounts(4);
+        let mut page = pagination("/v1/accounts?sort=-created,name", 3, "-created,name");
+        let sort = page.sort(accounts::Column::Id, SORTABLE).unwrap();
+        let first = sort.page(rows.clone());
+        assert_eq!(first.data, rows[..3]);
+        assert!(first.has_more);
+        let cursor = first.next_cursor.unwrap();
+        assert_eq!(
+            first.links,
+            vec![format!(
+                "</v1/accounts?sort=-created,name&cursor={cursor}>; rel=\"next\""
+            )]
+        );
+
+        page.cursor = Some((Direction::After, cursor));
+        let sort = page.sort(accounts::Column::Id, SORTABLE).unwrap();
+        assert_eq!(
+            sort.values.as_ref().unwrap()[1],
+            Value::from(rows[2].name.as_str())
+        );
+        let second = sort.page(rows[3..].to_vec());
+        assert!(!second.has_more);
+        assert_eq!(second.next_cursor, None);
+        assert!(second.prev_cursor.is_some());
+    }
+
+    #[test]
+    fn test_prev_page() {
+        let rows = accounts(4);
+        let mut page = pagination("/v1/accounts", 3, "");
+        let cursor = page
+            .sort(accounts::Column::Id, &[])
+            .unwrap()
+            .cursor(&rows[3]);
+        page.cursor = Some((Direction::Before, cursor));
+        let sort = page.sort(accounts::Column::Id, &[]).unwrap();
+        // Fetched in reverse, nearest the cursor first
+        let prev = sort.page(rows[..3].iter().rev().cloned().collect());
+        assert_eq!(prev.data, rows[..3]);
+        assert!(!prev.has_more);
+        assert_eq!(prev.prev_cursor, None);
+        assert!(prev.next_cursor.is_some());
+    }
+
+    #[test]
+    fn test_cursor_from_other_sort() {
+        let mut page = pagination("/v1/accounts", 3, "");
+        let sort = page.sort(accounts::Column::Id, SORTABLE).unwrap();
+        page.cursor = Some((Direction::After, sort.cursor(&account("a"))));
+        page.sort = parse_sort("name").unwrap();
+        assert!(page.sort(accounts::Column::Id, SORTABLE).is_err());
+    }
+
+    #[test]
+    fn test_keyset_query() {
+        let mut page = pagination("/v1/accounts", 10, "-created,name");
+        let cursor = page
+            .sort(accounts::Column::Id, SORTABLE)
+            .unwrap()
+            .cursor(&account("a"));
+        page.cursor = Some((Direction::After, cursor));
+        let sort = page.sort(accounts::Column::Id, SORTABLE).unwrap();
+        let sql = Accounts::find()
+            .filter(accounts::Column::Deleted.is_null())
+            .keyset(&sort)
+            .build(DbBackend::Postgres)
+            .to_string();
+        assert!(
+            sql.contains(r#"IS NULL AND ("accounts"."created" < "#),
+            "{sql}"
+        );
+        assert!(sql.contains(r#"AND "accounts"."name" > 'a')"#), "{sql}");
+        assert!(
+            sql.ends_with(
+                r#"ORDER BY "accounts"."created" DESC, "accounts"."name" ASC, "accounts"."id" ASC LIMIT 11"#
+            ),
+            "{sql}"
+        );
+    }
+
+    #[test]
+    fn test_link_replaces_cursor() {
+        assert_eq!(
+            link(
+                &"/v1/users?limit=5&cursor=abc&sort=id".parse().unwrap(),
+                "before",
+                "def",
+                "prev"
+            ),
+            "</v1/users?limit=5&sort=id&before=def>; rel=\"prev\""
+        );
+    }
+
+    #[test]
+    fn test_plan_rows() {
+        let plan =
+            serde_json::json!([{ "Plan": { "Node Type": "Seq Scan", "Plan Rows": 1250.0 } }]);
+        assert_eq!(plan_rows(&plan), Some(1250));
+        assert_eq!(plan_rows(&serde_json::json!([])), None);
+    }
+
+    #[test]
+    fn test_total_omitted() {
+        let page = pagination("/v1/accounts", 3, "");
+        let mut page = page
+            .sort(accounts::Column::Id, &[])
+            .unwrap()
+            .page(accounts(1));
+        let json = serde_json::to_value(&page).unwrap();
+        assert!(json.get("total").is_none());
+        page.total = Some(Total {
+            count: 1,
+            exact: true,
+        });
+        let json = serde_json::to_value(&page).unwrap();
+        assert_eq!(
+            json["total"],
+            serde_json::json!({ "count": 1, "exact": true })
+        );
     }
 }
@@ -0,0 +1,234 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::Request,
+    handler::Handler,
+    http::Method,
+    middleware::{self, Next},
+    response::Response,
+    routing::{on, MethodFilter, MethodRouter},
+    Router,
+};
+use tracing::debug;
+
+use crate::error::Error;
+
+use super::{auth::AuthUser, ApiContext};
+
+/// Permissions required to call a route
+#[derive(Debug, Clone, Copy)]
+pub enum Permission {
+    /// The caller must hold at least one of the permissions
+    AnyOf(&'static [&'static str]),
+    /// The caller must hold every one of the permissions
+    AllOf(&'static [&'static str]),
+}
+
+impl Permission {
+    /// Check the permissions held by a caller against the requirement
+    pub fn is_satisfied_by(&self, held: &[String]) -> bool {
+        let has = |required: &&str| held.iter().any(|h| grants(h, required));
+        match self {
+            Self::AnyOf(required) => required.iter().any(has),
+            Self::AllOf(required) => required.iter().all(has),
+        }
+    }
+}
+
+/// Check whether a held permission grants a required one.
+///
+/// Permissions are `:` delimited segments. A `*` segment matches any single
+/// segment, and a trailing `*` matches all remaining segments, so `*` grants
+/// everything and `list:*` grants both `list:user` and `list:user:accounts`.
+pub fn grants(held: &str, required: &str) -> bool {
+    let mut held = held.split(':');
+    let mut required = required.split(':');
+    loop {
+        match (held.next(), required.next()) {
+            (Some("*"), Some(_)) if held.clone().next().is_none() => return true,
+            (Some(h), Some(r)) if h == "*" || h == r => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// A registered route and the permission required to call it,
+/// `None` for public routes
+#[derive(Debug, Clone, Copy)]
+pub struct RouteInfo {
+    pub path: &'static str,
+    pub permission: Option<Permission>,
+}
+
+/// Router builder that makes every route declare the permission required
+/// to call it, or be explicitly marked as public.
+///
+/// Permissions are enforced by a route layer before the handler runs,
+/// using the caller authenticated by [`super::auth::authenticate`].
+#[derive(Default)]
+pub struct Routes {
+    router: Router<Arc<ApiContext>>,
+    info: Vec<RouteInfo>,
+    public: Vec<(&'static str, Method)>,
+}
+
+impl Routes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a route that requires the caller to hold `permission`
+    pub fn route_with_permission(
+        mut self,
+        path: &'static str,
+        method_router: MethodRouter<Arc<ApiContext>>,
+        permission: Permission,
+    ) -> Self {
+        let method_router =
+            method_router.route_layer(middleware::from_fn(move |req: Request, next: Next| {
+                require(permission, req, next)
+            }));
+        self.router = self.router.route(path, method_router);
+        self.info.push(RouteInfo {
+            path,
+            permission: Some(permission),
+        });
+        self
+    }
+
+    /// Add a route that any authenticated caller can use
+    pub fn authenticated_route(
+        self,
+        path: &'static str,
+        method_router: MethodRouter<Arc<ApiContext>>,
+    ) -> Self {
+        self.route_with_permission(path, method_router, Permission::AllOf(&[]))
+    }
+
+    /// Add a route that can be called without authentication. Public
+    /// routes take a single method so only that method skips
+    /// authentication, other methods on the same path still require it.
+    pub fn public_route<H, T>(mut self, path: &'static str, method: Method, handler: H) -> Self
+    where
+        H: Handler<T, Arc<ApiContext>>,
+        T: 'static,
+    {
+        let filter = MethodFilter::try_from(method.clone())
+            .unwrap_or_else(|_| panic!("Unsupported method {method} for public route {path}"));
+        self.router = self.router.route(path, on(filter, handler));
+        self.info.push(RouteInfo {
+            path,
+            permission: None,
+        });
+        // GET routes answer HEAD requests too
+        if method == Method::GET {
+            self.public.push((path, Method::HEAD));
+        }
+        self.public.push((path, method));
+        self
+    }
+
+    pub fn merge(mut self, other: Routes) -> Self {
+        self.router = self.router.merge(other.router);
+        self.info.extend(other.info);
+        self.public.extend(other.public);
+        self
+    }
+
+    pub fn info(&self) -> &[RouteInfo] {
+        &self.info
+    }
+
+    /// Methods registered without a permission, by path
+    pub fn public_methods(&self) -> HashMap<&'static str, Vec<Method>> {
+        let mut methods: HashMap<_, Vec<_>> = HashMap::new();
+        for (path, method) in &self.public {
+            methods.entry(*path).or_default().push(method.clone());
+        }
+        methods
+    }
+
+    pub fn into_router(self) -> Router<Arc<ApiContext>> {
+        self.router
+    }
+}
+
+/// Reject the request unless the authenticated caller satisfies `permission`
+async fn require(permission: Permission, req: Request, next: Next) -> Result<Response, Error> {
+    let user = req
+        .extensions()
+        .get::<AuthUser>()
+        .ok_or(Error::Unauthorized)?;
+    if !permission.is_satisfied_by(&user.permissions) {
+        debug!("{} does not satisfy {:?}", user.subject, permission);
+        return Err(Error::Forbidden);
+    }
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Routes that are intentionally callable without a permission
+    const PUBLIC_ROUTES: &[&str] = &[
+        "/health",
+        "/.well-known/jwks.json",
+        "/v1/stripe/webhooks",
+        "/v1/exports/download",
+        "/v1/auth0/logs",
+    ];
+
+    fn held(permissions: &[&str]) -> Vec<String> {
+        permissions.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_routes_have_permissions() {
+        for route in super::super::routes().info() {
+            if route.permission.is_none() {
+                assert!(
+                    PUBLIC_ROUTES.contains(&route.path),
+                    "{} has no permission attached",
+                    route.path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_grants() {
+        assert!(grants("list:user", "list:user"));
+        assert!(!grants("list:user", "list:account"));
+        assert!(!grants("list:user", "list:user:accounts"));
+        assert!(!grants("list:user:accounts", "list:user"));
+    }
+
+    #[test]
+    fn test_grants_wildcard() {
+        assert!(grants("*", "list:user"));
+        assert!(grants("list:*", "list:user"));
+        assert!(grants("list:*", "list:user:accounts"));
+        assert!(grants("*:user", "delete:user"));
+        assert!(!grants("*:user", "delete:account"));
+        assert!(!grants("*:user", "list:user:accounts"));
+        assert!(!grants("list:*", "delete:user"));
+    }
+
+    #[test]
+    fn test_any_of() {
+        let permission = Permission::AnyOf(&["list:user", "list:account"]);
+        assert!(permission.is_satisfied_by(&held(&["list:account"])));
+        assert!(!permission.is_satisfied_by(&held(&["delete:account"])));
+        assert!(!permission.is_satisfied_by(&[]));
+    }
+
+    #[test]
+    fn test_all_of() {
+        let permission = Permission::AllOf(&["list:user", "list:account"]);
+        assert!(permission.is_satisfied_by(&held(&["list:user", "list:account"])));
+        assert!(permission.is_satisfied_by(&held(&["list:*"])));
+        assert!(!permission.is_satisfied_by(&held(&["list:user"])));
+    }
+}
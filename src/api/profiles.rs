@@ -0,0 +1,363 @@
+use std::{collections::HashSet, sync::Arc};
+
+use ::stripe::{CreateCustomer, Customer, CustomerId, Metadata, RequestStrategy, UpdateCustomer};
+use anyhow::anyhow;
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sea_orm::{
+    entity::*,
+    query::*,
+    sea_query::{Expr, OnConflict},
+};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::auth0::UserProfile;
+use crate::entity::{prelude::*, soft_delete::SoftDeleteFilter, users};
+use crate::error::Error;
+
+use super::{
+    permissions::Routes,
+    tasks::enqueue,
+    users::get_user_by_provider_id,
+    validation::{validate_email, validate_name},
+    ApiContext,
+};
+
+/// Name of the task that refreshes a user's profile from Auth0
+pub const SYNC_PROFILE_TASK: &str = "sync_profile";
+
+/// Name of the task that creates or updates a user's Stripe customer
+pub const SYNC_CUSTOMER_TASK: &str = "sync_stripe_customer";
+
+/// Stripe customer id of users whose customer hasn't been created yet
+pub const PENDING_CUSTOMER: &str = "placeholder";
+
+/// Auth0 log event types after which a user's profile may have changed:
+/// logins, signups, email and username changes and email verification
+const PROFILE_EVENTS: &[&str] = &["s", "ss", "sce", "scu", "sv"];
+
+/// A single entry of an Auth0 log stream batch
+#[derive(Debug, Deserialize)]
+pub struct LogEvent {
+    pub log_id: String,
+    pub data: LogEventData,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogEventData {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SyncProfilePayload {
+    provider_id: String,
+    log_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SyncCustomerPayload {
+    user_id: Uuid,
+    updated: DateTime<Utc>,
+}
+
+pub fn routes() -> Routes {
+    Routes::new().public_route("/v1/auth0/logs", Method::POST, log_stream_handler)
+}
+
+/// Copy provider profile fields onto a user. Names set through the API take
+/// precedence over the provider's. Only fields that differ are set, so an
+/// unchanged profile leaves the user unchanged.
+fn apply_profile(user: &mut users::ActiveModel, current: &users::Model, profile: UserProfile) {
+    let email = profile.email.and_then(|email| validate_email(&email).ok());
+    user.email.set_if_not_equals(email);
+    user.email_verified
+        .set_if_not_equals(profile.email_verified);
+    user.avatar_url.set_if_not_equals(profile.picture);
+    if !current.name_overridden {
+        let name = profile.name.and_then(|name| validate_name(&name).ok());
+        user.name.set_if_not_equals(name);
+    }
+}
+
+/// Create the user for a first login, with their profile taken from
+/// `/userinfo`. Failing to fetch the profile doesn't block the login, it's
+/// filled in by the next sync instead.
+///
+/// Concurrent first requests race to insert the same provider id, so the
+/// insert skips conflicting rows and the winner's row is read back. The
+/// customer sync is enqueued in the same transaction as the insert, so a
+/// failed enqueue can't leave a user without a Stripe customer.
+pub async fn provision_user(
+    ctx: &ApiContext,
+    provider_id: &str,
+    access_token: &str,
+) -> Result<users::Model, Error> {
+    let profile = if ctx.config.auth0_domain.is_empty() {
+        UserProfile::default()
+    } else {
+        ctx.auth0_client
+            .get_userinfo(access_token)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to fetch profile of {}: {:?}", provider_id, e);
+                UserProfile::default()
+            })
+    };
+    let id = Uuid::now_v7();
+    let user = users::ActiveModel {
+        id: Set(id),
+        provider_id: Set(provider_id.to_string()),
+        stripe_customer_id: Set(String::from(PENDING_CUSTOMER)),
+        name: Set(profile.name.and_then(|name| validate_name(&name).ok())),
+        email: Set(profile.email.and_then(|email| validate_email(&email).ok())),
+        email_verified: Set(profile.email_verified),
+        avatar_url: Set(profile.picture),
+        ..Default::default()
+    };
+    let txn = ctx.db.begin().await?;
+    let inserted = Users::insert(user)
+        .on_conflict(
+            OnConflict::column(users::Column::ProviderId)
+                .do_nothing()
+                .to_owned(),
+        )
+        .exec_without_returning(&txn)
+        .await?;
+    if inserted > 0 {
+        let user = Users::find_by_id(id)
+            .one(&txn)
+            .await?
+            .ok_or_else(|| anyhow!("Provisioned user {provider_id} not found"))?;
+        enqueue_customer_sync(&txn, &user).await?;
+    }
+    txn.commit().await?;
+    let user = get_user_by_provider_id(ctx, &provider_id.to_string(), true)
+        .await?
+        .ok_or_else(|| anyhow!("Provisioned user {provider_id} not found"))?;
+    Ok(user)
+}
+
+/// Queue a sync of the user's Stripe customer with their current details
+pub async fn enqueue_customer_sync(
+    db: &impl ConnectionTrait,
+    user: &users::Model,
+) -> Result<(), Error> {
+    let payload = SyncCustomerPayload {
+        user_id: user.id,
+        updated: user.updated.into(),
+    };
+    enqueue(db, SYNC_CUSTOMER_TASK, payload).await
+}
+
+/// Task handler that refreshes a user's profile through the management API
+pub async fn sync_profile(ctx: &ApiContext, payload: &serde_json::Value) -> Result<(), Error> {
+    let payload: SyncProfilePayload = serde_json::from_value(payload.clone())?;
+    let user = Users::find()
+        .filter(users::Column::ProviderId.eq(payload.provider_id.clone()))
+        .active::<Users>()
+        .one(&ctx.db)
+        .await?;
+    let Some(user) = user else {
+        return Ok(());
+    };
+    let Some(profile) = ctx.auth0_client.get_user(&payload.provider_id).await? else {
+        return Ok(());
+    };
+    let mut active: users::ActiveModel = user.clone().into();
+    apply_profile(&mut active, &user, profile);
+    if !active.is_changed() {
+        return Ok(());
+    }
+    active.updated = Set(DateTime::from(Utc::now()));
+    let updated = active.update(&ctx.db).await?;
+    ctx.users.invalidate(&updated.provider_id);
+    if updated.email != user.email || updated.name != user.name {
+        enqueue_customer_sync(&ctx.db, &updated).await?;
+    }
+    Ok(())
+}
+
+/// Task handler that creates the user's Stripe customer, or brings its
+/// email and name up to date when it already exists
+pub async fn sync_stripe_customer(
+    ctx: &ApiContext,
+    payload: &serde_json::Value,
+) -> Result<(), Error> {
+    let payload: SyncCustomerPayload = serde_json::from_value(payload.clone())?;
+    let user = Users::find_by_id(payload.user_id)
+        .active::<Users>()
+        .one(&ctx.db)
+        .await?;
+    let Some(user) = user else {
+        return Ok(());
+    };
+    if user.stripe_customer_id == PENDING_CUSTOMER {
+        let mut params = CreateCustomer::new();
+        params.email = user.email.as_deref();
+        params.name = user.name.as_deref();
+        params.metadata = Some(Metadata::from([(
+            String::from("user_id"),
+            user.id.to_string(),
+        )]));
+        // Retries and concurrent runs for the same version of the user reuse
+        // one key, so Stripe creates a single customer between them. Stripe
+        // rejects a key reused with different details, hence the version.
+        let key = format!("customer-{}-{}", user.id, user.updated.timestamp_micros());
+        let client = ctx
+            .stripe_client
+            .clone()
+            .with_strategy(RequestStrategy::Idempotent(key));
+        let customer = Customer::create(&client, params).await?;
+        // Another run may have created the customer in the meantime
+        Users::update_many()
+            .col_expr(
+                users::Column::StripeCustomerId,
+                Expr::value(customer.id.to_string()),
+            )
+            .filter(users::Column::Id.eq(user.id))
+            .filter(users::Column::StripeCustomerId.eq(PENDING_CUSTOMER))
+            .exec(&ctx.db)
+            .await?;
+        ctx.users.invalidate(&user.provider_id);
+    } else if let Ok(customer_id) = user.stripe_customer_id.parse::<CustomerId>() {
+        let mut params = UpdateCustomer::new();
+        params.email = user.email.as_deref();
+        params.name = user.name.as_deref();
+        Customer::update(&ctx.stripe_client, &customer_id, params).await?;
+    }
+    Ok(())
+}
+
+/// Users whose profile may have changed in a batch of log events, along
+/// with the id of the event that changed it. Each user is only synced once
+/// per batch.
+fn changed_users(events: &[LogEvent]) -> Vec<(&str, &str)> {
+    let mut seen = HashSet::new();
+    events
+        .iter()
+        .filter(|event| PROFILE_EVENTS.contains(&event.data.kind.as_str()))
+        .filter_map(|event| Some((event.data.user_id.as_deref()?, event.log_id.as_str())))
+        .filter(|(user_id, _)| seen.insert(*user_id))
+        .collect()
+}
+
+/// Receives batches from an Auth0 custom webhook log stream, authenticated
+/// by the shared token configured on the stream, which is compared in
+/// constant time. Profiles are refreshed in the background so Auth0 isn't
+/// kept waiting.
+async fn log_stream_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    headers: HeaderMap,
+    body: Result<Json<Vec<LogEvent>>, JsonRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let token = &ctx.config.auth0_log_stream_token;
+    if token.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    let authorized =
+        authorization.is_some_and(|value| value.as_bytes().ct_eq(token.as_bytes()).into());
+    if !authorized {
+        return Err(Error::Unauthorized);
+    }
+    let Json(events) = body?;
+    for (provider_id, log_id) in changed_users(&events) {
+        let payload = SyncProfilePayload {
+            provider_id: provider_id.to_string(),
+            log_id: log_id.to_string(),
+        };
+        enqueue(&ctx.db, SYNC_PROFILE_TASK, payload).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(log_id: &str, kind: &str, user_id: Option<&str>) -> LogEvent {
+        LogEvent {
+            log_id: log_id.to_string(),
+            data: LogEventData {
+                kind: kind.to_string(),
+                user_id: user_id.map(ToString::to_string),
+            },
+        }
+    }
+
+    fn user(name: Option<&str>, name_overridden: bool) -> users::Model {
+        users::Model {
+            name: name.map(ToString::to_string),
+            name_overridden,
+            ..users::Model::for_tests("auth0|1")
+        }
+    }
+
+    fn profile(name: &str) -> UserProfile {
+        UserProfile {
+            name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_apply_profile_syncs_provider_name() {
+        let current = user(Some("Old"), false);
+        let mut active: users::ActiveModel = current.clone().into();
+        apply_profile(&mut active, &current, profile("New"));
+        assert_eq!(active.name, Set(Some(String::from("New"))));
+    }
+
+    #[test]
+    fn test_apply_profile_unchanged() {
+        let current = user(Some("Jo"), false);
+        let mut active: users::ActiveModel = current.clone().into();
+        apply_profile(&mut active, &current, profile("Jo"));
+        assert!(!active.is_changed());
+    }
+
+    #[test]
+    fn test_apply_profile_keeps_overridden_name() {
+        let current = user(Some("Chosen"), true);
+        let mut active: users::ActiveModel = current.clone().into();
+        apply_profile(&mut active, &current, profile("Provider"));
+        assert_eq!(active.name, Unchanged(Some(String::from("Chosen"))));
+    }
+
+    #[test]
+    fn test_changed_users() {
+        let events = [
+            event("1", "s", Some("auth0|1")),
+            event("2", "fp", Some("auth0|2")),
+            event("3", "sce", Some("auth0|1")),
+            event("4", "sv", None),
+            event("5", "sce", Some("auth0|3")),
+        ];
+        assert_eq!(
+            changed_users(&events),
+            vec![("auth0|1", "1"), ("auth0|3", "5")]
+        );
+    }
+
+    #[test]
+    fn test_parse_log_events() {
+        let events: Vec<LogEvent> = serde_json::from_str(
+            r#"[{"log_id":"90020","data":{"type":"sce","user_id":"auth0|1","date":"2024-01-01T00:00:00Z"}}]"#,
+        )
+        .unwrap();
+        assert_eq!(changed_users(&events), vec![("auth0|1", "90020")]);
+    }
+}
@@ -1,14 +1,29 @@
 use std::sync::Arc;
 
-use axum::{http::StatusCode, response::IntoResponse, routing::get, Router};
+use axum::{
+    extract::State,
+    http::{Method, StatusCode},
+    response::IntoResponse,
+    Json,
+};
 
-use super::ApiContext;
+use crate::error::Error;
 
-pub fn routes() -> Router<Arc<ApiContext>> {
-    Router::new().route("/health", get(healthcheck))
+use super::{permissions::Routes, ApiContext};
+
+pub fn routes() -> Routes {
+    Routes::new()
+        .public_route("/health", Method::GET, healthcheck)
+        .public_route("/.well-known/jwks.json", Method::GET, jwks)
 }
 
 // Handler for GET /health
 pub async fn healthcheck() -> impl IntoResponse {
     StatusCode::OK
 }
+
+// Handler for GET /.well-known/jwks.json, only served by the dev issuer
+pub async fn jwks(State(ctx): State<Arc<ApiContext>>) -> Result<impl IntoResponse, Error> {
+    let issuer = ctx.dev_issuer.as_ref().ok_or(Error::NotFound)?;
+    Ok(Json(issuer.jwks()))
+}
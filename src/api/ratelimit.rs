@@ -1,42 +1,379 @@
 use std::{
     net::{IpAddr, Ipv4Addr},
     sync::Arc,
+    time::Duration,
 };
 
 use axum::{
-    extract::{Request, State},
+    extract::{FromRequestParts, MatchedPath, Request, State},
+    http::{header::RETRY_AFTER, request::Parts, HeaderMap, HeaderName, HeaderValue, Method},
     middleware::Next,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
+use tracing::error;
 
-use crate::{error::Error, token_bucket::TokenBucket};
+use crate::{
+    config::Config,
+    error::Error,
+    rate_limit::{Decision, Limit},
+    token_bucket::Rate,
+};
 
-use super::ApiContext;
+use super::{
+    auth::{AuthUser, Principal},
+    memberships::AccountAccess,
+    ApiContext,
+};
 
 const IP_HEADER: &str = "X-Real-IP";
 const DEFAULT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
+const LIMIT_HEADER: HeaderName = HeaderName::from_static("ratelimit-limit");
+const REMAINING_HEADER: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RESET_HEADER: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// Routes whose `:id` is an account
+const ACCOUNT_ROUTES: &str = "/v1/accounts/:id";
+
+/// The route group a request falls in and its limit, `None` when the route
+/// is exempt. Reads and writes are limited separately so a burst of one
+/// doesn't lock a caller out of the other.
+fn policy(config: &Config, method: &Method, path: Option<&str>) -> Option<(&'static str, Limit)> {
+    if path.is_some_and(|path| config.rate_limit_exempt.iter().any(|exempt| exempt == path)) {
+        return None;
+    }
+    let policy = match *method {
+        Method::GET | Method::HEAD | Method::OPTIONS => (
+            "read",
+            Limit {
+                capacity: config.rate_limit_capacity,
+                fill_rate: Rate::new(config.rate_limit_fill_rate, config.rate_limit_fill_period),
+                take_rate: config.rate_limit_take_rate,
+            },
+        ),
+        _ => (
+            "write",
+            Limit {
+                capacity: config.rate_limit_write_capacity,
+                fill_rate: Rate::new(
+                    config.rate_limit_write_fill_rate,
+                    config.rate_limit_write_fill_period,
+                ),
+                take_rate: config.rate_limit_take_rate,
+            },
+        ),
+    };
+    Some(policy)
+}
+
+/// The limit every request from a client IP shares, whatever its route
+fn ip_policy(config: &Config) -> Limit {
+    Limit {
+        capacity: config.rate_limit_ip_capacity,
+        fill_rate: Rate::new(
+            config.rate_limit_ip_fill_rate,
+            config.rate_limit_ip_fill_period,
+        ),
+        take_rate: config.rate_limit_take_rate,
+    }
+}
+
+/// The account an account scoped route is counted against. Only members are,
+/// once their membership is checked, so outsiders can't use up an account's
+/// limit. Global admins aren't members and keep their own limit.
+async fn member_account(ctx: &Arc<ApiContext>, parts: &mut Parts) -> Option<String> {
+    let access = AccountAccess::from_request_parts(parts, ctx).await.ok()?;
+    access
+        .membership
+        .map(|membership| membership.account_id.to_string())
+}
+
+/// Who a request is counted against. Users are limited by account when
+/// `account` is given and otherwise by user id, services by client id, and
+/// anonymous callers by IP.
+fn caller(user: Option<&AuthUser>, account: Option<&str>, ip: IpAddr) -> String {
+    let Some(user) = user else {
+        return format!("ip:{ip}");
+    };
+    // Services act across many accounts, they shouldn't use up any one
+    // account's limit
+    if let Some(account) = account.filter(|_| !user.is_service()) {
+        return format!("account:{account}");
+    }
+    match &user.principal {
+        Principal::User(user) => format!("user:{}", user.id),
+        Principal::Service { client_id } => format!("client:{client_id}"),
+    }
+}
+
+/// The address a request came from, as reported by the proxy in front
+fn client_ip(headers: &HeaderMap) -> IpAddr {
+    headers
+        .get(IP_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_IP)
+}
+
+/// Whole seconds, rounded up so clients don't come back early
+fn seconds(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_add(u64::from(duration.subsec_nanos() > 0))
+}
+
+/// Add the IETF draft `RateLimit-*` headers, and `Retry-After` when the
+/// request was refused. Counts are in requests rather than tokens.
+fn rate_limit_headers(headers: &mut HeaderMap, decision: &Decision) {
+    let take_rate = decision.limit.take_rate.max(1);
+    let limit = decision.limit.capacity / take_rate;
+    headers.insert(LIMIT_HEADER, HeaderValue::from(limit));
+    headers.insert(
+        REMAINING_HEADER,
+        HeaderValue::from(decision.remaining / take_rate),
+    );
+    headers.insert(RESET_HEADER, HeaderValue::from(seconds(decision.reset)));
+    if let Some(retry_after) = decision.retry_after {
+        headers.insert(RETRY_AFTER, HeaderValue::from(seconds(retry_after)));
+    }
+}
+
+/// Rate limits requests by client IP before they're authenticated, so
+/// requests with bad, expired or revoked tokens use up a bucket and users
+/// are only provisioned while under the limit. Exempt routes are skipped.
+/// Responses that [`limiter`] didn't get to, like authentication failures,
+/// carry this bucket's headers instead.
+pub async fn ip_limiter(
+    State(ctx): State<Arc<ApiContext>>,
+    path: Option<MatchedPath>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let path = path.as_ref().map(MatchedPath::as_str);
+    if policy(&ctx.config, req.method(), path).is_none() {
+        return next.run(req).await;
+    }
+    let key = format!("all:{}", caller(None, None, client_ip(req.headers())));
+    let decision = match ctx.rate_limit.take(&key, ip_policy(&ctx.config)).await {
+        Ok(decision) => decision,
+        Err(e) => {
+            error!("Failed to check rate limit: {:?}", e);
+            return next.run(req).await;
+        }
+    };
+    let mut response = if decision.allowed {
+        next.run(req).await
+    } else {
+        Error::TooManyRequests.into_response()
+    };
+    if !response.headers().contains_key(LIMIT_HEADER) {
+        rate_limit_headers(response.headers_mut(), &decision);
+    }
+    response
+}
+
+/// Rate limits requests by route group and caller. Runs after
+/// [`super::auth::authenticate`] so authenticated callers are limited by
+/// identity rather than by the address they happen to call from, on top of
+/// [`ip_limiter`].
 pub async fn limiter(
     State(ctx): State<Arc<ApiContext>>,
+    path: Option<MatchedPath>,
     req: Request,
     next: Next,
-) -> Result<impl IntoResponse, Error> {
-    let ip = req
-        .headers()
-        .get(IP_HEADER)
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(DEFAULT_IP);
-    let mut bucket = ctx.rate_limit.entry(ip).or_insert_with(|| {
-        TokenBucket::new(
-            ctx.config.rate_limit_capacity,
-            ctx.config.rate_limit_fill_rate,
-            ctx.config.rate_limit_take_rate,
-        )
-    });
-    if bucket.take() {
-        Ok(next.run(req).await)
+) -> Response {
+    let path = path.as_ref().map(MatchedPath::as_str);
+    let Some((group, limit)) = policy(&ctx.config, req.method(), path) else {
+        return next.run(req).await;
+    };
+    let ip = client_ip(req.headers());
+    let by_account = ctx.config.rate_limit_by_account
+        && path.is_some_and(|path| path.starts_with(ACCOUNT_ROUTES));
+    let (req, account) = if by_account {
+        let (mut parts, body) = req.into_parts();
+        let account = member_account(&ctx, &mut parts).await;
+        (Request::from_parts(parts, body), account)
     } else {
-        Err(Error::TooManyRequests)
+        (req, None)
+    };
+    let key = format!(
+        "{group}:{}",
+        caller(req.extensions().get::<AuthUser>(), account.as_deref(), ip)
+    );
+    // Requests are let through when the store can't be reached rather than
+    // taking the API down with it
+    let decision = match ctx.rate_limit.take(&key, limit).await {
+        Ok(decision) => decision,
+        Err(e) => {
+            error!("Failed to check rate limit: {:?}", e);
+            return next.run(req).await;
+        }
+    };
+    let mut response = if decision.allowed {
+        next.run(req).await
+    } else {
+        Error::TooManyRequests.into_response()
+    };
+    rate_limit_headers(response.headers_mut(), &decision);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header::AUTHORIZATION, StatusCode},
+        middleware,
+        routing::get,
+        Extension, Router,
+    };
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        api::{app, memberships::ACCOUNT_ADMIN},
+        entity::users,
+    };
+
+    fn user() -> AuthUser {
+        AuthUser {
+            principal: Principal::User(users::Model::for_tests("auth0|1")),
+            subject: String::from("auth0|1"),
+            permissions: vec![],
+        }
+    }
+
+    fn service(client_id: &str) -> AuthUser {
+        AuthUser {
+            principal: Principal::Service {
+                client_id: client_id.to_string(),
+            },
+            subject: format!("{client_id}@clients"),
+            permissions: vec![],
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let config = Config::default();
+        assert!(policy(&config, &Method::POST, Some("/v1/stripe/webhooks")).is_none());
+        assert!(policy(&config, &Method::GET, Some("/health")).is_none());
+        let (group, limit) = policy(&config, &Method::GET, Some("/v1/users")).unwrap();
+        assert_eq!(group, "read");
+        assert_eq!(limit.capacity, config.rate_limit_capacity);
+        let (group, limit) = policy(&config, &Method::DELETE, Some("/v1/users/:id")).unwrap();
+        assert_eq!(group, "write");
+        assert_eq!(limit.capacity, config.rate_limit_write_capacity);
+        assert!(policy(&config, &Method::GET, None).is_some());
+    }
+
+    #[test]
+    fn test_caller() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(caller(None, Some("acct"), ip), "ip:10.0.0.1");
+        let user = user();
+        let id = user.user().unwrap().id;
+        assert_eq!(caller(Some(&user), None, ip), format!("user:{id}"));
+        assert_eq!(caller(Some(&user), Some("acct"), ip), "account:acct");
+        let service = service("abc");
+        assert_eq!(caller(Some(&service), None, ip), "client:abc");
+        assert_eq!(caller(Some(&service), Some("acct"), ip), "client:abc");
+    }
+
+    #[tokio::test]
+    async fn test_non_members_keep_their_own_limit() {
+        let config = Config {
+            rate_limit_capacity: 1,
+            rate_limit_by_account: true,
+            ..Config::default()
+        };
+        let ctx = Arc::new(ApiContext::for_tests(config));
+        let account = Uuid::now_v7();
+        // Admins reach every account without being a member of it
+        for _ in 0..2 {
+            let admin = AuthUser {
+                permissions: vec![ACCOUNT_ADMIN.to_string()],
+                ..user()
+            };
+            let app = Router::new()
+                .route("/v1/accounts/:id", get(|| async {}))
+                .layer(middleware::from_fn_with_state(ctx.clone(), limiter))
+                .layer(Extension(admin))
+                .with_state(ctx.clone());
+            let request = axum::http::Request::get(format!("/v1/accounts/{account}"))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_headers() {
+        let mut headers = HeaderMap::new();
+        let decision = Decision {
+            allowed: false,
+            limit: Limit {
+                capacity: 100,
+                fill_rate: Rate::per_second(1),
+                take_rate: 2,
+            },
+            remaining: 1,
+            reset: Duration::from_millis(99_500),
+            retry_after: Some(Duration::from_millis(500)),
+        };
+        rate_limit_headers(&mut headers, &decision);
+        assert_eq!(headers["ratelimit-limit"], "50");
+        assert_eq!(headers["ratelimit-remaining"], "0");
+        assert_eq!(headers["ratelimit-reset"], "100");
+        assert_eq!(headers[RETRY_AFTER], "1");
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_responses_have_headers() {
+        let app = app(
+            super::super::routes(),
+            Arc::new(ApiContext::for_tests(Config::default())),
+        );
+        let request = axum::http::Request::get("/v1/users")
+            .header(AUTHORIZATION, "Bearer invalid")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["ratelimit-limit"], "1000");
+        assert_eq!(response.headers()["ratelimit-remaining"], "999");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_tokens_are_limited() {
+        let config = Config {
+            rate_limit_ip_capacity: 1,
+            ..Config::default()
+        };
+        let app = app(
+            super::super::routes(),
+            Arc::new(ApiContext::for_tests(config)),
+        );
+        let request = || {
+            axum::http::Request::get("/v1/users")
+                .header(AUTHORIZATION, "Bearer invalid")
+                .header(IP_HEADER, "10.0.0.1")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+    }
+
+    #[test]
+    fn test_seconds() {
+        assert_eq!(seconds(Duration::ZERO), 0);
+        assert_eq!(seconds(Duration::from_millis(1)), 1);
+        assert_eq!(seconds(Duration::MAX), u64::MAX);
     }
 }
@@ -0,0 +1,244 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection},
+        Path, State,
+    },
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sea_orm::{entity::*, query::*};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::entity::{
+    prelude::*,
+    revocations::{self, RevocationKind},
+    soft_delete::SoftDeleteFilter,
+};
+use crate::error::Error;
+use crate::revocation::Revocation;
+
+use super::{
+    auth::decode_claims,
+    pagination::{Page, Pagination},
+    permissions::{Permission, Routes},
+    ApiContext,
+};
+
+/// What a revocation applies to
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RevocationTarget {
+    /// A single token by `jti`, optionally kept only until the token expires
+    Token {
+        jti: String,
+        expires: Option<DateTime<Utc>>,
+    },
+    /// Every token issued to a subject
+    Subject { subject: String },
+    /// Tokens issued to a subject before a point in time, defaulting to now
+    IssuedBefore {
+        subject: String,
+        issued_before: Option<DateTime<Utc>>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateRevocation {
+    #[serde(flatten)]
+    pub target: RevocationTarget,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IntrospectToken {
+    pub token: String,
+}
+
+/// Token introspection response, loosely following RFC 7662
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TokenIntrospection {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Vec<String>>,
+}
+
+pub fn routes() -> Routes {
+    Routes::new()
+        .route_with_permission(
+            "/v1/revocations",
+            get(list_revocations_handler),
+            Permission::AllOf(&["list:revocation"]),
+        )
+        .route_with_permission(
+            "/v1/revocations",
+            post(create_revocation_handler),
+            Permission::AllOf(&["create:revocation"]),
+        )
+        .route_with_permission(
+            "/v1/revocations/:id",
+            delete(delete_revocation_handler),
+            Permission::AllOf(&["delete:revocation"]),
+        )
+        .route_with_permission(
+            "/v1/tokens/introspect",
+            post(introspect_token_handler),
+            Permission::AllOf(&["introspect:token"]),
+        )
+}
+
+/// Convert a stored revocation into a cache entry
+fn to_revocation(model: &revocations::Model) -> Option<Revocation> {
+    match model.kind {
+        RevocationKind::Token => model.jti.clone().map(Revocation::Token),
+        RevocationKind::Subject => model.subject.clone().map(Revocation::Subject),
+        RevocationKind::IssuedBefore => model
+            .subject
+            .clone()
+            .zip(model.issued_before)
+            .map(|(subject, before)| Revocation::IssuedBefore(subject, before.into())),
+    }
+}
+
+/// Reload the in-memory revocation list from the database
+pub async fn refresh_revocations(ctx: &ApiContext) -> Result<(), Error> {
+    let now = Utc::now();
+    let active = Revocations::find()
+        .active::<Revocations>()
+        .filter(
+            Condition::any()
+                .add(revocations::Column::Expires.is_null())
+                .add(revocations::Column::Expires.gt(now)),
+        )
+        .all(&ctx.db)
+        .await?;
+    ctx.revocations
+        .replace(active.iter().filter_map(to_revocation));
+    Ok(())
+}
+
+pub async fn list_revocations(
+    ctx: &Arc<ApiContext>,
+    page: &Pagination,
+) -> Result<Page<revocations::Model>, Error> {
+    let sort = page.sort(revocations::Column::Id, &[])?;
+    let query = Revocations::find().active::<Revocations>();
+    sort.fetch(ctx, query).await
+}
+
+pub async fn create_revocation(
+    ctx: &Arc<ApiContext>,
+    revocation: CreateRevocation,
+) -> Result<revocations::Model, Error> {
+    let mut model = revocations::ActiveModel {
+        id: Set(Uuid::now_v7()),
+        reason: Set(revocation.reason),
+        ..Default::default()
+    };
+    match revocation.target {
+        RevocationTarget::Token { jti, expires } => {
+            model.kind = Set(RevocationKind::Token);
+            model.jti = Set(Some(jti));
+            model.expires = Set(expires.map(DateTime::from));
+        }
+        RevocationTarget::Subject { subject } => {
+            model.kind = Set(RevocationKind::Subject);
+            model.subject = Set(Some(subject));
+        }
+        RevocationTarget::IssuedBefore {
+            subject,
+            issued_before,
+        } => {
+            let issued_before = issued_before.unwrap_or_else(Utc::now);
+            model.kind = Set(RevocationKind::IssuedBefore);
+            model.subject = Set(Some(subject));
+            model.issued_before = Set(Some(DateTime::from(issued_before)));
+        }
+    }
+    let model = model.insert(&ctx.db).await?;
+    if let Some(revocation) = to_revocation(&model) {
+        ctx.revocations.insert(revocation);
+    }
+    Ok(model)
+}
+
+pub async fn delete_revocation(
+    ctx: &Arc<ApiContext>,
+    id: Uuid,
+) -> Result<revocations::Model, Error> {
+    let revocation = Revocations::find_by_id(id)
+        .active::<Revocations>()
+        .one(&ctx.db)
+        .await?;
+    let revocation = revocation.ok_or(Error::NotFound)?;
+    let mut revocation: revocations::ActiveModel = revocation.into();
+    let now = DateTime::from(Utc::now());
+    revocation.deleted = Set(Some(now));
+    revocation.updated = Set(now);
+    let revocation = revocation.update(&ctx.db).await?;
+    // Entries can't be removed from the cached list individually
+    refresh_revocations(ctx).await?;
+    Ok(revocation)
+}
+
+/// Describe a token, reporting revoked or invalid tokens as inactive
+pub fn introspect_token(ctx: &ApiContext, token: &str) -> TokenIntrospection {
+    match decode_claims(ctx, token) {
+        Ok(claims) if !ctx.revocations.is_revoked(&claims) => TokenIntrospection {
+            active: true,
+            permissions: Some(claims.permissions()),
+            sub: Some(claims.sub),
+            jti: claims.jti,
+            iat: claims.iat,
+            exp: claims.exp,
+        },
+        _ => TokenIntrospection::default(),
+    }
+}
+
+async fn list_revocations_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    page: Pagination,
+) -> Result<impl IntoResponse, Error> {
+    let revocations = list_revocations(&ctx, &page).await?;
+    Ok(revocations)
+}
+
+async fn create_revocation_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    body: Result<Json<CreateRevocation>, JsonRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Json(body) = body?;
+    let created = create_revocation(&ctx, body).await?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+async fn delete_revocation_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    revocation_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Path(revocation_id) = revocation_id?;
+    let deleted = delete_revocation(&ctx, revocation_id).await?;
+    Ok(Json(deleted))
+}
+
+async fn introspect_token_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    body: Result<Json<IntrospectToken>, JsonRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Json(body) = body?;
+    Ok(Json(introspect_token(&ctx, &body.token)))
+}
@@ -0,0 +1,45 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, Query},
+    http::request::Parts,
+    RequestPartsExt,
+};
+
+use crate::error::Error;
+
+use super::{auth::AuthUser, ApiContext};
+
+/// Permission required to see soft deleted rows
+pub const READ_DELETED: &str = "read:deleted";
+
+/// Whether soft deleted rows should be included, set with the
+/// `include_deleted=true` query parameter. Opting in requires the
+/// `read:deleted` permission, everyone else only ever sees active rows.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IncludeDeleted(pub bool);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IncludeDeleted
+where
+    Arc<ApiContext>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(params) = parts.extract::<Query<HashMap<String, String>>>().await?;
+        let include_deleted = match params.get("include_deleted") {
+            Some(value) => value.parse::<bool>().map_err(|_| {
+                Error::BadRequest(String::from("include_deleted must be true or false"))
+            })?,
+            None => false,
+        };
+        if include_deleted {
+            AuthUser::from_request_parts(parts, state)
+                .await?
+                .has_permission(READ_DELETED)?;
+        }
+        Ok(Self(include_deleted))
+    }
+}
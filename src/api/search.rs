@@ -0,0 +1,289 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    response::IntoResponse,
+    routing::get,
+    Json,
+};
+use sea_orm::{entity::*, query::*, sea_query::Expr, FromQueryResult};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::entity::{accounts, prelude::*, soft_delete::SoftDeleteFilter, users, users_accounts};
+use crate::error::Error;
+
+use super::{
+    auth::AuthUser,
+    memberships::ACCOUNT_ADMIN,
+    permissions::{Permission, Routes},
+    ApiContext,
+};
+
+/// Results returned when `limit` isn't given
+const DEFAULT_LIMIT: u64 = 10;
+
+/// Most results a search can return
+const MAX_LIMIT: u64 = 50;
+
+/// Longest query accepted
+const MAX_QUERY_LENGTH: usize = 100;
+
+/// Documents searched, these must match the expressions indexed in
+/// `0010_create_search_indexes` for the indexes to be used
+const ACCOUNT_DOCUMENT: &str = r#"to_tsvector('simple', "accounts"."name")"#;
+const USER_DOCUMENT: &str = r#"to_tsvector('simple', coalesce("users"."name", '') || ' ' || coalesce("users"."email", ''))"#;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+/// A search match, tagged with the kind of record it is
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SearchResult {
+    Account {
+        id: Uuid,
+        name: String,
+        rank: f32,
+    },
+    User {
+        id: Uuid,
+        name: Option<String>,
+        email: Option<String>,
+        rank: f32,
+    },
+}
+
+impl SearchResult {
+    fn rank(&self) -> f32 {
+        match self {
+            Self::Account { rank, .. } | Self::User { rank, .. } => *rank,
+        }
+    }
+}
+
+#[derive(Debug, FromQueryResult)]
+struct AccountMatch {
+    id: Uuid,
+    name: String,
+    rank: f32,
+}
+
+#[derive(Debug, FromQueryResult)]
+struct UserMatch {
+    id: Uuid,
+    name: Option<String>,
+    email: Option<String>,
+    rank: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchResults {
+    pub data: Vec<SearchResult>,
+}
+
+/// The records a caller's search covers
+#[derive(Debug, Clone, Copy)]
+pub struct SearchScope {
+    pub users: bool,
+    pub accounts: bool,
+    /// Limits accounts to those this user belongs to
+    pub member: Option<Uuid>,
+}
+
+pub fn routes() -> Routes {
+    Routes::new().route_with_permission(
+        "/v1/search",
+        get(search_handler),
+        Permission::AnyOf(&["list:account", "list:user"]),
+    )
+}
+
+/// A pattern matching `query` anywhere in a value, with `LIKE` wildcards in
+/// the query escaped
+fn contains_pattern(query: &str) -> String {
+    let mut pattern = String::with_capacity(query.len() + 2);
+    pattern.push('%');
+    for c in query.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Accounts matching a query, ranked, limited to `member`'s accounts when given
+fn account_query(query: &str, pattern: &str, member: Option<Uuid>) -> Select<Accounts> {
+    let mut select = Accounts::find()
+        .select_only()
+        .column(accounts::Column::Id)
+        .column(accounts::Column::Name)
+        .column_as(
+            Expr::cust_with_values(
+                format!(
+                    r#"GREATEST(ts_rank({ACCOUNT_DOCUMENT}, plainto_tsquery('simple', $1)), similarity("accounts"."name", $1))"#
+                ),
+                [query],
+            ),
+            "rank",
+        )
+        .filter(Expr::cust_with_values(
+            format!(
+                r#"({ACCOUNT_DOCUMENT} @@ plainto_tsquery('simple', $1) OR "accounts"."name" ILIKE $2)"#
+            ),
+            [query, pattern],
+        ))
+        .active::<Accounts>();
+    if let Some(user_id) = member {
+        select = select
+            .join(
+                JoinType::InnerJoin,
+                users_accounts::Relation::Accounts.def().rev(),
+            )
+            .filter(users_accounts::Column::UserId.eq(user_id))
+            .active::<UsersAccounts>();
+    }
+    select
+}
+
+/// Users matching a query, ranked
+fn user_query(query: &str, pattern: &str) -> Select<Users> {
+    Users::find()
+        .select_only()
+        .column(users::Column::Id)
+        .column(users::Column::Name)
+        .column(users::Column::Email)
+        .column_as(
+            Expr::cust_with_values(
+                format!(
+                    r#"GREATEST(ts_rank({USER_DOCUMENT}, plainto_tsquery('simple', $1)), similarity("users"."name", $1), similarity("users"."email", $1))"#
+                ),
+                [query],
+            ),
+            "rank",
+        )
+        .filter(Expr::cust_with_values(
+            format!(
+                r#"({USER_DOCUMENT} @@ plainto_tsquery('simple', $1) OR "users"."name" ILIKE $2 OR "users"."email" ILIKE $2)"#
+            ),
+            [query, pattern],
+        ))
+        .active::<Users>()
+}
+
+/// Search accounts and users by name and email, matching whole words through
+/// full text search and partial values through trigram indexes. Results are
+/// ordered by rank, best match first.
+pub async fn search(
+    ctx: &Arc<ApiContext>,
+    query: &str,
+    limit: u64,
+    scope: SearchScope,
+) -> Result<Vec<SearchResult>, Error> {
+    let query = query.trim();
+    if query.is_empty() || query.chars().count() > MAX_QUERY_LENGTH {
+        return Err(Error::BadRequest(format!(
+            "q must be between 1 and {MAX_QUERY_LENGTH} characters"
+        )));
+    }
+    let pattern = contains_pattern(query);
+    let mut results = Vec::new();
+
+    if scope.accounts {
+        let accounts = account_query(query, &pattern, scope.member)
+            .order_by_desc(Expr::cust("rank"))
+            .order_by_asc(accounts::Column::Id)
+            .limit(limit)
+            .into_model::<AccountMatch>()
+            .all(&ctx.db)
+            .await?;
+        results.extend(accounts.into_iter().map(|account| SearchResult::Account {
+            id: account.id,
+            name: account.name,
+            rank: account.rank,
+        }));
+    }
+
+    if scope.users {
+        let users = user_query(query, &pattern)
+            .order_by_desc(Expr::cust("rank"))
+            .order_by_asc(users::Column::Id)
+            .limit(limit)
+            .into_model::<UserMatch>()
+            .all(&ctx.db)
+            .await?;
+        results.extend(users.into_iter().map(|user| SearchResult::User {
+            id: user.id,
+            name: user.name,
+            email: user.email,
+            rank: user.rank,
+        }));
+    }
+
+    results.sort_by(|a, b| b.rank().total_cmp(&a.rank()));
+    results.truncate(usize::try_from(limit).unwrap_or(usize::MAX));
+    Ok(results)
+}
+
+/// Users are only searched by callers allowed to list them, and accounts
+/// are limited to the caller's own unless they hold [`ACCOUNT_ADMIN`]
+async fn search_handler(
+    user: AuthUser,
+    State(ctx): State<Arc<ApiContext>>,
+    query: Result<Query<SearchQuery>, QueryRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Query(query) = query?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let accounts = user.has_permission("list:account").is_ok();
+    let member = if accounts && user.has_permission(ACCOUNT_ADMIN).is_err() {
+        Some(user.user()?.id)
+    } else {
+        None
+    };
+    let scope = SearchScope {
+        users: user.has_permission("list:user").is_ok(),
+        accounts,
+        member,
+    };
+    let data = search(&ctx, &query.q, limit, scope).await?;
+    Ok(Json(SearchResults { data }))
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::DbBackend;
+
+    use super::*;
+
+    #[test]
+    fn test_contains_pattern() {
+        assert_eq!(contains_pattern("acme"), "%acme%");
+        assert_eq!(contains_pattern(r"50%_off\"), r"%50\%\_off\\%");
+    }
+
+    #[test]
+    fn test_account_query() {
+        let member = Uuid::now_v7();
+        let sql = account_query("acme", "%acme%", Some(member))
+            .build(DbBackend::Postgres)
+            .to_string();
+        assert!(
+            sql.contains(r#"similarity("accounts"."name", 'acme')) AS "rank""#),
+            "{sql}"
+        );
+        assert!(
+            sql.contains(r#"OR "accounts"."name" ILIKE '%acme%')"#),
+            "{sql}"
+        );
+        assert!(
+            sql.contains(&format!(r#""users_accounts"."user_id" = '{member}'"#)),
+            "{sql}"
+        );
+    }
+}
@@ -1,14 +1,18 @@
-use axum::{extract::State, http::HeaderMap, response::IntoResponse, routing::post, Router};
+use axum::{
+    extract::State,
+    http::{HeaderMap, Method},
+    response::IntoResponse,
+};
 use reqwest::StatusCode;
 use std::sync::Arc;
 use stripe::Webhook;
 
 use crate::error::Error;
 
-use super::ApiContext;
+use super::{permissions::Routes, ApiContext};
 
-pub fn routes() -> Router<Arc<ApiContext>> {
-    Router::new().route("/v1/stripe/webhooks", post(stripe_webhook_handler))
+pub fn routes() -> Routes {
+    Routes::new().public_route("/v1/stripe/webhooks", Method::POST, stripe_webhook_handler)
 }
 
 // Handler for GET /v1/stripe/webhooks
@@ -0,0 +1,177 @@
+use chrono::{DateTime, Duration, Utc};
+use sea_orm::{
+    entity::*,
+    query::*,
+    sea_query::{LockBehavior, LockType, OnConflict},
+    ConnectionTrait,
+};
+use serde::Serialize;
+use tracing::{debug, error, warn};
+
+use crate::entity::{
+    prelude::*,
+    tasks::{self, TaskState},
+};
+use crate::error::Error;
+
+use super::{exports, profiles, ApiContext};
+
+/// Queue background tasks are enqueued on and claimed from
+const QUEUE: &str = "main";
+
+/// Attempts made before a task is marked as failed
+const MAX_ATTEMPTS: i16 = 3;
+
+/// Delay before retrying a failed task, multiplied by the attempt number
+const RETRY_BACKOFF: Duration = Duration::seconds(30);
+
+/// Enqueue a task to be run by a worker. Name and payload together are
+/// unique, enqueueing the same task twice is a no-op, so include an id in
+/// the payload of tasks that can repeat.
+pub async fn enqueue<C: ConnectionTrait>(
+    db: &C,
+    name: &str,
+    payload: impl Serialize,
+) -> Result<(), Error> {
+    let task = tasks::ActiveModel {
+        name: Set(name.to_string()),
+        queue: Set(QUEUE.to_string()),
+        payload: Set(serde_json::to_value(payload)?),
+        max_attempts: Set(MAX_ATTEMPTS),
+        ..Default::default()
+    };
+    Tasks::insert(task)
+        .on_conflict(
+            OnConflict::columns([tasks::Column::Name, tasks::Column::Payload])
+                .do_nothing()
+                .to_owned(),
+        )
+        .exec_without_returning(db)
+        .await?;
+    Ok(())
+}
+
+/// Tasks that can be claimed at `now`: those due to run, and running tasks
+/// whose worker hasn't finished them within `visibility_timeout`
+fn runnable(now: DateTime<Utc>, visibility_timeout: std::time::Duration) -> Select<Tasks> {
+    let due = Condition::all()
+        .add(tasks::Column::State.is_in([TaskState::Created, TaskState::Scheduled]))
+        .add(
+            Condition::any()
+                .add(tasks::Column::ScheduledAt.is_null())
+                .add(tasks::Column::ScheduledAt.lte(now)),
+        );
+    let abandoned = Condition::all()
+        .add(tasks::Column::State.eq(TaskState::Running))
+        .add(tasks::Column::AttemptedAt.lt(now - visibility_timeout));
+    Tasks::find()
+        .filter(tasks::Column::Queue.eq(QUEUE))
+        .filter(Condition::any().add(due).add(abandoned))
+        .order_by_asc(tasks::Column::Priority)
+        .order_by_asc(tasks::Column::ScheduledAt)
+        .order_by_asc(tasks::Column::Id)
+}
+
+/// Claim the next runnable task, marking it as running. Tasks locked by
+/// other workers are skipped, so any number of replicas can poll at once.
+/// Reclaiming an abandoned task counts its lost run as an attempt.
+async fn claim(ctx: &ApiContext) -> Result<Option<tasks::Model>, Error> {
+    let txn = ctx.db.begin().await?;
+    let now = Utc::now();
+    let task = runnable(now, ctx.config.task_visibility_timeout)
+        .lock_with_behavior(LockType::Update, LockBehavior::SkipLocked)
+        .one(&txn)
+        .await?;
+    let Some(task) = task else {
+        return Ok(None);
+    };
+    let attempt = task.attempt + 1;
+    let mut task: tasks::ActiveModel = task.into();
+    task.state = Set(TaskState::Running);
+    task.attempt = Set(attempt);
+    task.attempted_at = Set(Some(DateTime::from(now)));
+    let task = task.update(&txn).await?;
+    txn.commit().await?;
+    Ok(Some(task))
+}
+
+/// Run a claimed task by name
+async fn run(ctx: &ApiContext, task: &tasks::Model) -> Result<(), Error> {
+    match task.name.as_str() {
+        exports::EXPORT_TASK => exports::generate_export(ctx, &task.payload).await,
+        profiles::SYNC_PROFILE_TASK => profiles::sync_profile(ctx, &task.payload).await,
+        profiles::SYNC_CUSTOMER_TASK => profiles::sync_stripe_customer(ctx, &task.payload).await,
+        name => Err(anyhow::anyhow!("Unknown task {name}").into()),
+    }
+}
+
+/// Record the outcome of a task, rescheduling failures until they run out
+/// of attempts
+async fn finish(
+    ctx: &ApiContext,
+    task: tasks::Model,
+    result: Result<(), Error>,
+) -> Result<(), Error> {
+    let (id, name, attempt, max_attempts) =
+        (task.id, task.name.clone(), task.attempt, task.max_attempts);
+    let payload = task.payload.clone();
+    let mut task: tasks::ActiveModel = task.into();
+    match result {
+        Ok(()) => {
+            debug!("Task {} ({}) completed", id, name);
+            task.state = Set(TaskState::Completed);
+        }
+        Err(e) if attempt < max_attempts => {
+            warn!("Task {} ({}) failed, retrying: {:?}", id, name, e);
+            task.state = Set(TaskState::Scheduled);
+            let delay = RETRY_BACKOFF * i32::from(attempt);
+            task.scheduled_at = Set(Some(DateTime::from(Utc::now() + delay)));
+        }
+        Err(e) => {
+            error!("Task {} ({}) failed: {:?}", id, name, e);
+            task.state = Set(TaskState::Failed);
+            if name == exports::EXPORT_TASK {
+                exports::fail_export(ctx, &payload).await?;
+            }
+        }
+    }
+    task.update(&ctx.db).await?;
+    Ok(())
+}
+
+/// Run every runnable task, returning once the queue is drained. Abandoned
+/// tasks that have used up their attempts are failed without running again.
+pub async fn work(ctx: &ApiContext) -> Result<(), Error> {
+    while let Some(task) = claim(ctx).await? {
+        let result = if task.attempt > task.max_attempts {
+            Err(anyhow::anyhow!("Task abandoned after {} attempts", task.max_attempts).into())
+        } else {
+            run(ctx, &task).await
+        };
+        finish(ctx, task, result).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::DbBackend;
+
+    use super::*;
+
+    #[test]
+    fn test_runnable_includes_abandoned_tasks() {
+        let now = Utc::now();
+        let sql = runnable(now, std::time::Duration::from_secs(600))
+            .build(DbBackend::Postgres)
+            .to_string();
+        assert!(sql.contains(r#""tasks"."state" IN (0, 1)"#), "{sql}");
+        let stale = (now - Duration::minutes(10)).format("%Y-%m-%d %H:%M:%S");
+        assert!(
+            sql.contains(&format!(
+                r#""tasks"."state" = 2 AND "tasks"."attempted_at" < '{stale}"#
+            )),
+            "{sql}"
+        );
+    }
+}
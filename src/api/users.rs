@@ -7,165 +7,390 @@ use axum::{
     },
     response::IntoResponse,
     routing::{delete, get, patch, post},
-    Json, Router,
+    Json,
 };
 use chrono::{DateTime, Utc};
 use sea_orm::{entity::*, query::*};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::entity::{accounts, prelude::*, users};
+use crate::entity::{accounts, prelude::*, soft_delete::SoftDeleteFilter, users, users_accounts};
 use crate::error::Error;
 
-use super::{auth::AuthUser, pagination::Pagination, ApiContext};
+use super::{
+    accounts::{expand_accounts, ExpandedAccount, ACCOUNT_SORT_KEYS},
+    auth::AuthUser,
+    expand::{Expand, Fields},
+    filters::{self, Filters, ParseValue},
+    pagination::{Page, Pagination},
+    permissions::{Permission, Routes},
+    profiles::enqueue_customer_sync,
+    scope::IncludeDeleted,
+    validation::{non_null, validate_email, validate_name},
+    ApiContext,
+};
+
+/// Fields users can be sorted by besides `id`. Nullable columns are left out
+/// since keyset pagination can't step over nulls.
+pub const USER_SORT_KEYS: &[(&str, users::Column)] = &[
+    ("provider_id", users::Column::ProviderId),
+    ("created", users::Column::Created),
+    ("updated", users::Column::Updated),
+];
+
+/// Fields users can be filtered by
+pub const USER_FILTERS: &[(&str, users::Column, ParseValue)] = &[
+    ("provider_id", users::Column::ProviderId, filters::text),
+    ("email", users::Column::Email, filters::text),
+    (
+        "email_verified",
+        users::Column::EmailVerified,
+        filters::boolean,
+    ),
+    ("created", users::Column::Created, filters::timestamp),
+    ("updated", users::Column::Updated, filters::timestamp),
+];
+
+/// Relations that can be expanded on users, with the permission needed.
+/// Accounts can have their own relations expanded in turn.
+pub const USER_EXPANSIONS: &[(&str, &str)] = &[
+    ("accounts", "list:user:accounts"),
+    ("accounts.users", "list:user:account"),
+    ("accounts.subscription", "retrieve:subscription"),
+];
+
+/// A user with its requested relations embedded
+#[derive(Debug, Serialize)]
+pub struct ExpandedUser {
+    #[serde(flatten)]
+    pub user: users::Model,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accounts: Option<Vec<ExpandedAccount>>,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateUser {
     pub provider_id: String,
     pub stripe_customer_id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub avatar_url: Option<String>,
 }
 
+/// Partial user update. Absent fields are left untouched, and explicit
+/// `null`s are rejected for columns that can't be null.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpdateUser {
-    pub provider_id: Option<String>,
-    pub stripe_customer_id: Option<String>,
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    pub provider_id: Option<Option<String>>,
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    pub stripe_customer_id: Option<Option<String>>,
+    /// Display name, which the provider's no longer overwrites. `null` clears
+    /// it and goes back to the provider's name on the next sync.
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    pub name: Option<Option<String>>,
 }
 
-pub fn routes() -> Router<Arc<ApiContext>> {
-    Router::new()
-        .route("/v1/users", get(list_users_handler))
-        .route("/v1/users/:id", get(get_user_by_id_handler))
-        .route("/v1/users", post(create_user_handler))
-        .route("/v1/users/:id", patch(update_user_handler))
-        .route("/v1/users/:id", delete(delete_user_handler))
-        .route("/v1/users/:id/accounts", get(list_user_accounts_handler))
+pub fn routes() -> Routes {
+    Routes::new()
+        .route_with_permission(
+            "/v1/users",
+            get(list_users_handler),
+            Permission::AllOf(&["list:user"]),
+        )
+        .route_with_permission(
+            "/v1/users/:id",
+            get(get_user_by_id_handler),
+            Permission::AllOf(&["retrieve:user"]),
+        )
+        .route_with_permission(
+            "/v1/users",
+            post(create_user_handler),
+            Permission::AllOf(&["create:user"]),
+        )
+        .route_with_permission(
+            "/v1/users/:id",
+            patch(update_user_handler),
+            Permission::AllOf(&["update:user"]),
+        )
+        .route_with_permission(
+            "/v1/users/:id",
+            delete(delete_user_handler),
+            Permission::AllOf(&["delete:user"]),
+        )
+        .route_with_permission(
+            "/v1/users/:id/restore",
+            post(restore_user_handler),
+            Permission::AllOf(&["restore:user"]),
+        )
+        .route_with_permission(
+            "/v1/users/:id/accounts",
+            get(list_user_accounts_handler),
+            Permission::AllOf(&["list:user:accounts"]),
+        )
 }
 
 pub async fn list_users(
     ctx: &Arc<ApiContext>,
     page: &Pagination,
-) -> Result<Vec<users::Model>, Error> {
-    let users = Users::find()
-        .filter(users::Column::Id.gte(page.after))
-        .order_by_asc(users::Column::Id)
-        .limit(page.limit)
-        .all(&ctx.db)
-        .await?;
-    Ok(users)
+    filters: &Filters,
+    include_deleted: bool,
+) -> Result<Page<users::Model>, Error> {
+    let sort = page.sort(users::Column::Id, USER_SORT_KEYS)?;
+    let query = Users::find()
+        .scoped::<Users>(include_deleted)
+        .filter(filters.condition(USER_FILTERS)?);
+    sort.fetch(ctx, query).await
 }
 
 pub async fn create_user(ctx: &Arc<ApiContext>, user: CreateUser) -> Result<users::Model, Error> {
+    let name = user.name.as_deref().map(validate_name).transpose()?;
+    let email = user.email.as_deref().map(validate_email).transpose()?;
     let user = users::ActiveModel {
         id: Set(Uuid::now_v7()),
         provider_id: Set(user.provider_id),
         stripe_customer_id: Set(user.stripe_customer_id),
+        name_overridden: Set(name.is_some()),
+        name: Set(name),
+        email: Set(email),
+        email_verified: Set(user.email_verified),
+        avatar_url: Set(user.avatar_url),
         ..Default::default()
     };
     let user = user.insert(&ctx.db).await?;
     Ok(user)
 }
 
-pub async fn get_user_by_id(ctx: &Arc<ApiContext>, id: Uuid) -> Result<users::Model, Error> {
+pub async fn get_user_by_id(
+    ctx: &Arc<ApiContext>,
+    id: Uuid,
+    include_deleted: bool,
+) -> Result<users::Model, Error> {
     Users::find_by_id(id)
+        .scoped::<Users>(include_deleted)
         .one(&ctx.db)
         .await?
         .ok_or(Error::NotFound)
 }
 
+/// Find a user by their identity provider id. Provider ids stay reserved
+/// by soft deleted users, so callers provisioning users need to see them.
 pub async fn get_user_by_provider_id(
     ctx: &ApiContext,
     id: &String,
+    include_deleted: bool,
 ) -> Result<Option<users::Model>, Error> {
     let user = Users::find()
         .filter(users::Column::ProviderId.eq(id))
+        .scoped::<Users>(include_deleted)
         .one(&ctx.db)
         .await?;
     Ok(user)
 }
 
+/// Apply a partial update to a user. A changed name is passed on to the
+/// user's Stripe customer in the background.
 pub async fn update_user(
     ctx: &Arc<ApiContext>,
     id: Uuid,
     update: UpdateUser,
 ) -> Result<users::Model, Error> {
-    let user = Users::find_by_id(id).one(&ctx.db).await?;
+    let provider_id = non_null("provider_id", update.provider_id)?;
+    let stripe_customer_id = non_null("stripe_customer_id", update.stripe_customer_id)?;
+    let name = update
+        .name
+        .map(|name| name.map(|name| validate_name(&name)).transpose())
+        .transpose()?;
+    let user = Users::find_by_id(id).active::<Users>().one(&ctx.db).await?;
     let user = user.ok_or(Error::NotFound)?;
+    // The user is cached under its old provider id until that's dropped too
+    let previous_provider_id = user.provider_id.clone();
+    let previous_name = user.name.clone();
     let mut user: users::ActiveModel = user.into();
-    if let Some(provider_id) = update.provider_id {
+    if let Some(provider_id) = provider_id {
         user.provider_id = Set(provider_id);
     }
-    if let Some(stripe_customer_id) = update.stripe_customer_id {
+    if let Some(stripe_customer_id) = stripe_customer_id {
         user.stripe_customer_id = Set(stripe_customer_id);
     }
-    let user = user.update(&ctx.db).await?;
+    if let Some(name) = name {
+        user.name_overridden = Set(name.is_some());
+        user.name = Set(name);
+    }
+    user.updated = Set(DateTime::from(Utc::now()));
+    let txn = ctx.db.begin().await?;
+    let user = user.update(&txn).await?;
+    if user.name != previous_name {
+        enqueue_customer_sync(&txn, &user).await?;
+    }
+    txn.commit().await?;
+    ctx.users.invalidate(&previous_provider_id);
+    ctx.users.invalidate(&user.provider_id);
     Ok(user)
 }
 
 pub async fn delete_user(ctx: &Arc<ApiContext>, id: Uuid) -> Result<users::Model, Error> {
-    let user = Users::find_by_id(id).one(&ctx.db).await?;
+    let user = Users::find_by_id(id).active::<Users>().one(&ctx.db).await?;
+    let user = user.ok_or(Error::NotFound)?;
+    let mut user: users::ActiveModel = user.into();
+    let now = DateTime::from(Utc::now());
+    user.deleted = Set(Some(now));
+    user.updated = Set(now);
+    let user = user.update(&ctx.db).await?;
+    ctx.users.invalidate(&user.provider_id);
+    Ok(user)
+}
+
+/// Undo a soft delete, the user can log in again afterwards
+pub async fn restore_user(ctx: &Arc<ApiContext>, id: Uuid) -> Result<users::Model, Error> {
+    let user = Users::find_by_id(id)
+        .deleted::<Users>()
+        .one(&ctx.db)
+        .await?;
     let user = user.ok_or(Error::NotFound)?;
     let mut user: users::ActiveModel = user.into();
-    user.deleted = Set(Some(DateTime::from(Utc::now())));
+    user.deleted = Set(None);
+    user.updated = Set(DateTime::from(Utc::now()));
     let user = user.update(&ctx.db).await?;
+    ctx.users.invalidate(&user.provider_id);
     Ok(user)
 }
 
+/// List the accounts a user belongs to, empty for users without any
 pub async fn list_user_accounts(
     ctx: &ApiContext,
     id: Uuid,
     page: &Pagination,
-) -> Result<Vec<accounts::Model>, Error> {
-    let result = Users::find()
-        .find_with_related(Accounts)
-        .filter(users::Column::Id.eq(id))
-        .filter(accounts::Column::Id.gte(page.after))
-        .order_by_asc(accounts::Column::Id)
-        .limit(page.limit)
-        .all(&ctx.db)
+    include_deleted: bool,
+) -> Result<Page<accounts::Model>, Error> {
+    let sort = page.sort(accounts::Column::Id, ACCOUNT_SORT_KEYS)?;
+    Users::find_by_id(id)
+        .scoped::<Users>(include_deleted)
+        .one(&ctx.db)
+        .await?
+        .ok_or(Error::NotFound)?;
+    let query = Accounts::find()
+        .join(
+            JoinType::InnerJoin,
+            users_accounts::Relation::Accounts.def().rev(),
+        )
+        .filter(users_accounts::Column::UserId.eq(id))
+        .scoped::<Accounts>(include_deleted)
+        .scoped::<UsersAccounts>(include_deleted);
+    sort.fetch(ctx, query).await
+}
+
+/// Embed the requested relations in users, loading each relation for
+/// every user at once
+pub async fn expand_users(
+    ctx: &ApiContext,
+    users: Vec<users::Model>,
+    expand: &Expand,
+) -> Result<Vec<ExpandedUser>, Error> {
+    if !expand.contains("accounts") {
+        let expanded = users
+            .into_iter()
+            .map(|user| ExpandedUser {
+                user,
+                accounts: None,
+            })
+            .collect();
+        return Ok(expanded);
+    }
+    let memberships = users
+        .load_many(
+            UsersAccounts::find()
+                .active::<UsersAccounts>()
+                .order_by_asc(users_accounts::Column::AccountId),
+            &ctx.db,
+        )
         .await?;
-    let (_, accounts) = result.first().ok_or(Error::NotFound)?.to_owned();
-    Ok(accounts)
+    let counts: Vec<usize> = memberships.iter().map(Vec::len).collect();
+    let memberships: Vec<_> = memberships.into_iter().flatten().collect();
+    let mut loaded = memberships
+        .load_one(Accounts::find().active::<Accounts>(), &ctx.db)
+        .await?
+        .into_iter();
+    let accounts: Vec<Vec<_>> = counts
+        .into_iter()
+        .map(|count| loaded.by_ref().take(count).flatten().collect())
+        .collect();
+    // Every user's accounts are expanded together so nested relations are
+    // loaded once
+    let counts: Vec<usize> = accounts.iter().map(Vec::len).collect();
+    let accounts = accounts.into_iter().flatten().collect();
+    let mut accounts = expand_accounts(ctx, accounts, &expand.nested("accounts"))
+        .await?
+        .into_iter();
+    let expanded = users
+        .into_iter()
+        .zip(counts)
+        .map(|(user, count)| ExpandedUser {
+            user,
+            accounts: Some(accounts.by_ref().take(count).collect()),
+        })
+        .collect();
+    Ok(expanded)
 }
 
 async fn list_users_handler(
     user: AuthUser,
     State(ctx): State<Arc<ApiContext>>,
     page: Pagination,
+    filters: Filters,
+    fields: Fields,
+    expand: Expand,
+    IncludeDeleted(include_deleted): IncludeDeleted,
 ) -> Result<impl IntoResponse, Error> {
-    user.has_permission("list:user")?;
-    let users = list_users(&ctx, &page).await?;
-    Ok(Json(users))
+    fields.validate::<Users>()?;
+    expand.check(&user, USER_EXPANSIONS)?;
+    let mut page = list_users(&ctx, &page, &filters, include_deleted).await?;
+    let users = expand_users(&ctx, std::mem::take(&mut page.data), &expand).await?;
+    let users = users
+        .into_iter()
+        .map(|user| fields.apply(user, &expand))
+        .collect::<Result<_, _>>()?;
+    Ok(page.with_data(users))
 }
 
 async fn get_user_by_id_handler(
     user: AuthUser,
     State(ctx): State<Arc<ApiContext>>,
     user_id: Result<Path<Uuid>, PathRejection>,
+    fields: Fields,
+    expand: Expand,
+    IncludeDeleted(include_deleted): IncludeDeleted,
 ) -> Result<impl IntoResponse, Error> {
-    user.has_permission("retrieve:user")?;
     let Path(user_id) = user_id?;
-    let found = get_user_by_id(&ctx, user_id).await?;
-    Ok(Json(found))
+    fields.validate::<Users>()?;
+    expand.check(&user, USER_EXPANSIONS)?;
+    let found = get_user_by_id(&ctx, user_id, include_deleted).await?;
+    let found = expand_users(&ctx, vec![found], &expand)
+        .await?
+        .pop()
+        .ok_or(Error::NotFound)?;
+    Ok(Json(fields.apply(found, &expand)?))
 }
 
 async fn create_user_handler(
-    user: AuthUser,
     State(ctx): State<Arc<ApiContext>>,
     body: Result<Json<CreateUser>, JsonRejection>,
 ) -> Result<impl IntoResponse, Error> {
-    user.has_permission("create:user")?;
     let Json(body) = body?;
     let created = create_user(&ctx, body).await?;
     Ok(Json(created))
 }
 
 async fn update_user_handler(
-    user: AuthUser,
     State(ctx): State<Arc<ApiContext>>,
     user_id: Result<Path<Uuid>, PathRejection>,
     body: Result<Json<UpdateUser>, JsonRejection>,
 ) -> Result<impl IntoResponse, Error> {
-    user.has_permission("update:user")?;
     let Path(user_id) = user_id?;
     let Json(body) = body?;
     let updated = update_user(&ctx, user_id, body).await?;
@@ -173,24 +398,30 @@ async fn update_user_handler(
 }
 
 async fn delete_user_handler(
-    user: AuthUser,
     State(ctx): State<Arc<ApiContext>>,
     user_id: Result<Path<Uuid>, PathRejection>,
 ) -> Result<impl IntoResponse, Error> {
-    user.has_permission("delete:user")?;
     let Path(user_id) = user_id?;
     delete_user(&ctx, user_id).await?;
     Ok(())
 }
 
+async fn restore_user_handler(
+    State(ctx): State<Arc<ApiContext>>,
+    user_id: Result<Path<Uuid>, PathRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Path(user_id) = user_id?;
+    let restored = restore_user(&ctx, user_id).await?;
+    Ok(Json(restored))
+}
+
 async fn list_user_accounts_handler(
-    user: AuthUser,
     State(ctx): State<Arc<ApiContext>>,
     user_id: Result<Path<Uuid>, PathRejection>,
     page: Pagination,
+    IncludeDeleted(include_deleted): IncludeDeleted,
 ) -> Result<impl IntoResponse, Error> {
-    user.has_permission("list:user:accounts")?;
     let Path(user_id) = user_id?;
-    let found = list_user_accounts(&ctx, user_id, &page).await?;
-    Ok(Json(found))
+    let found = list_user_accounts(&ctx, user_id, &page, include_deleted).await?;
+    Ok(found)
 }
@@ -0,0 +1,72 @@
+use crate::error::Error;
+
+/// Longest name accepted for named resources, in characters
+const MAX_NAME_LENGTH: usize = 128;
+
+/// Trim a resource name and check it isn't blank or too long
+pub fn validate_name(name: &str) -> Result<String, Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(Error::BadRequest(String::from("name must not be blank")));
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(Error::BadRequest(format!(
+            "name must be at most {MAX_NAME_LENGTH} characters"
+        )));
+    }
+    Ok(name.to_string())
+}
+
+/// Normalize an email address and check it looks deliverable. Full
+/// validation is left to whoever sends mail to it.
+pub fn validate_email(email: &str) -> Result<String, Error> {
+    let email = email.trim().to_lowercase();
+    match email.split_once('@') {
+        Some((local, domain)) if !local.is_empty() && domain.contains('.') => Ok(email),
+        _ => Err(Error::BadRequest(String::from(
+            "email must be a valid email address",
+        ))),
+    }
+}
+
+/// Unwrap a patched field for a column that can't be null. Absent fields
+/// are left untouched, an explicit `null` is rejected.
+pub fn non_null<T>(field: &str, value: Option<Option<T>>) -> Result<Option<T>, Error> {
+    match value {
+        Some(None) => Err(Error::BadRequest(format!("{field} must not be null"))),
+        Some(Some(value)) => Ok(Some(value)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert_eq!(validate_name("  Lawn Care ").unwrap(), "Lawn Care");
+        assert!(validate_name("").is_err());
+        assert!(validate_name("   ").is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LENGTH)).is_ok());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_validate_email() {
+        assert_eq!(
+            validate_email(" Jake@Example.com ").unwrap(),
+            "jake@example.com"
+        );
+        assert!(validate_email("jake").is_err());
+        assert!(validate_email("@example.com").is_err());
+        assert!(validate_email("jake@localhost").is_err());
+    }
+
+    #[test]
+    fn test_non_null() {
+        assert_eq!(non_null("name", Some(Some(1))).unwrap(), Some(1));
+        assert_eq!(non_null::<i32>("name", None).unwrap(), None);
+        assert!(non_null::<i32>("name", Some(None)).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::{anyhow, Result};
-use jsonwebtoken::{decode, jwk::AlgorithmParameters, DecodingKey, Validation};
+use jsonwebtoken::{decode, jwk::AlgorithmParameters, Algorithm, DecodingKey, Validation};
 use jsonwebtoken::{
     jwk::{Jwk, JwkSet},
     TokenData,
@@ -16,31 +16,123 @@ pub struct Client {
     domain: String,
     client_id: String,
     client_secret: String,
+    jwks_url: String,
+    audience: Option<String>,
     jwk_cache: HashMap<String, Jwk>,
 }
 
+/// Grant type Auth0 sets on tokens issued to machine-to-machine applications
+const CLIENT_CREDENTIALS_GRANT: &str = "client-credentials";
+
+/// Subject suffix Auth0 uses for machine-to-machine applications
+const CLIENT_SUBJECT_SUFFIX: &str = "@clients";
+
+/// Profile of a user as returned by `/userinfo` and the management API,
+/// which share field names
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct UserProfile {
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub picture: Option<String>,
+}
+
 /// The claims in the JWT token
 #[derive(Debug, Clone, Deserialize)]
 pub struct AuthClaims {
     pub sub: String,
-    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub jti: Option<String>,
+    #[serde(default)]
+    pub iat: Option<i64>,
+    #[serde(default)]
+    pub exp: Option<i64>,
+    #[serde(default)]
+    pub permissions: Option<Vec<String>>,
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub gty: Option<String>,
+}
+
+impl AuthClaims {
+    /// Whether the token was issued to a machine-to-machine application
+    /// through the client credentials grant
+    pub fn is_client_credentials(&self) -> bool {
+        self.gty.as_deref() == Some(CLIENT_CREDENTIALS_GRANT)
+            || self.sub.ends_with(CLIENT_SUBJECT_SUFFIX)
+    }
+
+    /// The client id of a machine-to-machine token, if this is one
+    pub fn client_id(&self) -> Option<&str> {
+        if !self.is_client_credentials() {
+            return None;
+        }
+        Some(
+            self.sub
+                .strip_suffix(CLIENT_SUBJECT_SUFFIX)
+                .unwrap_or(&self.sub),
+        )
+    }
+
+    /// Permissions granted by the token. Machine-to-machine tokens carry
+    /// their grants in the space delimited `scope` claim instead of
+    /// `permissions`, so both are merged for those.
+    pub fn permissions(&self) -> Vec<String> {
+        let mut permissions = self.permissions.clone().unwrap_or_default();
+        if self.is_client_credentials() {
+            if let Some(scope) = &self.scope {
+                for s in scope.split_whitespace() {
+                    if !permissions.iter().any(|p| p == s) {
+                        permissions.push(s.to_string());
+                    }
+                }
+            }
+        }
+        permissions
+    }
 }
 
 impl Client {
     pub fn new(domain: String, client_id: String, client_secret: String) -> Self {
+        let jwks_url = format!("https://{}/.well-known/jwks.json", domain);
         Self {
             domain,
             client_id,
             client_secret,
+            jwks_url,
+            audience: None,
             jwk_cache: HashMap::new(),
         }
     }
 
+    /// Fetch the JWK set from a full URL instead of the tenant's well known
+    /// location, e.g. a local issuer served over plain http
+    pub fn with_jwks_url(mut self, jwks_url: String) -> Self {
+        self.jwks_url = jwks_url;
+        self
+    }
+
+    /// Require tokens to be issued for this API audience. Without one the
+    /// `aud` claim isn't checked.
+    pub fn with_audience(mut self, audience: String) -> Self {
+        self.audience = Some(audience);
+        self
+    }
+
     /// Fetch and cache a JWK set from an Auth0 tenant
     pub async fn load_jwk(&mut self) -> Result<(), Error> {
-        let url = format!("https://{}/.well-known/jwks.json", self.domain);
-        let body = reqwest::get(url).await?.text().await?;
+        let body = reqwest::get(&self.jwks_url).await?.text().await?;
         let jwks: JwkSet = serde_json::from_str(&body)?;
+        self.add_jwks(jwks)
+    }
+
+    /// Cache the keys of a JWK set
+    pub fn add_jwks(&mut self, jwks: JwkSet) -> Result<(), Error> {
         for jwk in jwks.keys {
             let kid = jwk
                 .common
@@ -59,7 +151,11 @@ impl Client {
                 AlgorithmParameters::RSA(ref rsa) => {
                     let decoding_key = DecodingKey::from_rsa_components(&rsa.n, &rsa.e)
                         .map_err(|_| Error::Auth0)?;
-                    let validation = Validation::default();
+                    let mut validation = Validation::new(Algorithm::RS256);
+                    match &self.audience {
+                        Some(audience) => validation.set_audience(&[audience]),
+                        None => validation.validate_aud = false,
+                    }
                     let decoded_token = decode::<AuthClaims>(token, &decoding_key, &validation)
                         .map_err(|_| Error::Auth0)?;
                     Ok(decoded_token)
@@ -71,6 +167,61 @@ impl Client {
         }
     }
 
+    /// Fetch the profile of the user an access token was issued to
+    pub async fn get_userinfo(&self, access_token: &str) -> Result<UserProfile, Error> {
+        let url = format!("https://{}/userinfo", self.domain);
+        let profile = reqwest::Client::new()
+            .get(url)
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(profile)
+    }
+
+    /// Fetch a user's profile through the management API, `None` if the
+    /// user doesn't exist
+    pub async fn get_user(&self, user_id: &str) -> Result<Option<UserProfile>, Error> {
+        let token = self.get_management_access_token().await?;
+        let response = reqwest::Client::new()
+            .get(self.management_user_url(user_id)?)
+            .bearer_auth(token)
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    /// Management API url of a user, escaping the `|` in user ids
+    fn management_user_url(&self, user_id: &str) -> Result<reqwest::Url, Error> {
+        let mut url = reqwest::Url::parse(&format!("https://{}/api/v2/users", self.domain))
+            .map_err(|e| anyhow!(e))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid management API url"))?
+            .push(user_id);
+        Ok(url)
+    }
+
+    /// Delete a user through the management API. Users that are already
+    /// gone are treated as deleted.
+    pub async fn delete_user(&self, user_id: &str) -> Result<(), Error> {
+        let token = self.get_management_access_token().await?;
+        let response = reqwest::Client::new()
+            .delete(self.management_user_url(user_id)?)
+            .bearer_auth(token)
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
+
     /// Use the client credentials grant to get a management API access token
     async fn get_management_access_token(&self) -> Result<String, Error> {
         let url = format!("https://{}/oauth/token", self.domain);
@@ -97,3 +248,63 @@ impl Client {
         Ok(token.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(json: &str) -> AuthClaims {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_user_profile() {
+        let profile: UserProfile = serde_json::from_str(
+            r#"{"sub":"auth0|123","email":"a@example.com","email_verified":true,"picture":"https://example.com/a.png"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            profile,
+            UserProfile {
+                email: Some(String::from("a@example.com")),
+                email_verified: true,
+                name: None,
+                picture: Some(String::from("https://example.com/a.png")),
+            }
+        );
+        let profile: UserProfile = serde_json::from_str(r#"{"sub":"auth0|123"}"#).unwrap();
+        assert_eq!(profile, UserProfile::default());
+    }
+
+    #[test]
+    fn test_user_claims() {
+        let claims = claims(r#"{"sub":"auth0|123","permissions":["list:user"]}"#);
+        assert!(!claims.is_client_credentials());
+        assert_eq!(claims.client_id(), None);
+        assert_eq!(claims.permissions(), vec!["list:user"]);
+    }
+
+    #[test]
+    fn test_user_claims_ignore_scope() {
+        let claims = claims(r#"{"sub":"auth0|123","scope":"openid list:user"}"#);
+        assert!(claims.permissions().is_empty());
+    }
+
+    #[test]
+    fn test_client_credentials_claims() {
+        let claims = claims(
+            r#"{"sub":"abc@clients","gty":"client-credentials","scope":"list:user list:account"}"#,
+        );
+        assert!(claims.is_client_credentials());
+        assert_eq!(claims.client_id(), Some("abc"));
+        assert_eq!(claims.permissions(), vec!["list:user", "list:account"]);
+    }
+
+    #[test]
+    fn test_client_credentials_claims_merge_permissions() {
+        let claims = claims(
+            r#"{"sub":"abc@clients","permissions":["list:user"],"scope":"list:user list:account"}"#,
+        );
+        assert_eq!(claims.permissions(), vec!["list:user", "list:account"]);
+    }
+}
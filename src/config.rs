@@ -22,13 +22,52 @@ impl Default for Config {
             auth0_domain: String::new(),
             auth0_client_id: String::new(),
             auth0_client_secret: String::new(),
+            auth0_jwks_url: None,
+            auth0_audience: None,
+            auth0_log_stream_token: String::new(),
+            dev_issuer: false,
+            dev_issuer_key_path: String::from(".dev-issuer.der"),
             rate_limit_capacity: 100,
             rate_limit_fill_rate: 1,
+            rate_limit_fill_period: Duration::from_secs(1),
             rate_limit_take_rate: 1,
+            rate_limit_write_capacity: 20,
+            rate_limit_write_fill_rate: 1,
+            rate_limit_write_fill_period: Duration::from_secs(1),
+            rate_limit_ip_capacity: 1000,
+            rate_limit_ip_fill_rate: 20,
+            rate_limit_ip_fill_period: Duration::from_secs(1),
+            rate_limit_exempt: vec![
+                String::from("/health"),
+                String::from("/v1/stripe/webhooks"),
+                String::from("/v1/auth0/logs"),
+            ],
+            rate_limit_by_account: false,
+            rate_limit_store: RateLimitBackend::Memory,
+            rate_limit_batch: 10,
+            rate_limit_lease: Duration::from_secs(5),
+            revocation_refresh_interval: Duration::from_secs(30),
+            user_cache_ttl: Duration::from_secs(30),
+            signing_secret: String::new(),
+            invitation_ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            deleted_user_retention_days: 30,
+            purge_interval: Duration::from_secs(60 * 60),
+            export_ttl: Duration::from_secs(24 * 60 * 60),
+            task_poll_interval: Duration::from_secs(5),
+            task_visibility_timeout: Duration::from_secs(10 * 60),
+            count_estimate_threshold: 10_000,
         }
     }
 }
 
+/// Rate limit bucket storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBackend {
+    Memory,
+    Postgres,
+}
+
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -65,12 +104,114 @@ pub struct Config {
     // Auth0 client secret
     pub auth0_client_secret: String,
 
+    // Full JWKS URL, defaults to the Auth0 tenant's well known location
+    pub auth0_jwks_url: Option<String>,
+
+    // API audience tokens must be issued for, unchecked when unset
+    pub auth0_audience: Option<String>,
+
+    // Token Auth0 log streams authenticate with, the webhook is disabled
+    // when unset
+    pub auth0_log_stream_token: String,
+
+    // Accept tokens from the local development issuer
+    pub dev_issuer: bool,
+
+    // Where the development issuer's private key is stored
+    pub dev_issuer_key_path: String,
+
     // Rate limit bucket capacity
-    pub rate_limit_capacity: u8,
+    pub rate_limit_capacity: u32,
 
-    // Rate limit bucket fill rate per second
-    pub rate_limit_fill_rate: u8,
+    // Tokens added to a rate limit bucket every fill period
+    pub rate_limit_fill_rate: u32,
+
+    // Period the fill rate is spread over, e.g. a fill rate of 5 and a
+    // period of 60 adds a token every 12 seconds
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub rate_limit_fill_period: Duration,
 
     // Rate limit bucket take rate per request
-    pub rate_limit_take_rate: u8,
+    pub rate_limit_take_rate: u32,
+
+    // Rate limit bucket capacity for writes, POST, PUT, PATCH and DELETE
+    // requests, which are counted separately from reads
+    pub rate_limit_write_capacity: u32,
+
+    // Tokens added to a write bucket every write fill period
+    pub rate_limit_write_fill_rate: u32,
+
+    // Period the write fill rate is spread over
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub rate_limit_write_fill_period: Duration,
+
+    // Rate limit bucket capacity per client IP, checked before the request is
+    // authenticated so bad tokens are limited too. It should be well above
+    // the per caller limits, callers behind one address share it.
+    pub rate_limit_ip_capacity: u32,
+
+    // Tokens added to a client IP's bucket every IP fill period
+    pub rate_limit_ip_fill_rate: u32,
+
+    // Period the IP fill rate is spread over
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub rate_limit_ip_fill_period: Duration,
+
+    // Routes that are never rate limited, webhook senders retry on their own
+    // and health checks come from the load balancer
+    pub rate_limit_exempt: Vec<String>,
+
+    // Share one limit between everyone calling an account's routes, rather
+    // than limiting each user separately
+    pub rate_limit_by_account: bool,
+
+    // Where rate limit buckets are kept, `memory` limits each replica on its
+    // own while `postgres` shares the limit between them
+    pub rate_limit_store: RateLimitBackend,
+
+    // Tokens a replica reserves from a shared bucket at a time
+    pub rate_limit_batch: u32,
+
+    // How long reserved tokens can be held before they're given up
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub rate_limit_lease: Duration,
+
+    // How often the token revocation list is reloaded from the database
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub revocation_refresh_interval: Duration,
+
+    // How long authenticated users are cached, zero disables the cache
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub user_cache_ttl: Duration,
+
+    // Secret used to sign invitation tokens and other links the API issues
+    pub signing_secret: String,
+
+    // How long account invitations stay valid
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub invitation_ttl: Duration,
+
+    // Days soft deleted users are kept before they're purged for good
+    pub deleted_user_retention_days: u32,
+
+    // How often the purge job looks for users past retention
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub purge_interval: Duration,
+
+    // How long a generated data export can be downloaded
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub export_ttl: Duration,
+
+    // How often the background worker checks for new tasks
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub task_poll_interval: Duration,
+
+    // How long a task can run before it's assumed its worker died and it's
+    // claimed again, counting the lost run as a failed attempt
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub task_visibility_timeout: Duration,
+
+    // Listings estimated to match more rows than this report an estimated
+    // total instead of counting every row
+    pub count_estimate_threshold: u64,
 }
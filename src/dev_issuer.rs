@@ -0,0 +1,266 @@
+//! A local stand-in for Auth0 used in development and tests.
+//!
+//! The issuer signs tokens with an RSA key generated on first use and saved
+//! to disk, so tokens minted by the `token` subcommand are accepted by a
+//! server running in dev mode with the same key path.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use jsonwebtoken::{
+    encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, Jwk, JwkSet, KeyAlgorithm, PublicKeyUse,
+        RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, EncodingKey, Header,
+};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey},
+    traits::PublicKeyParts,
+    RsaPrivateKey,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Smallest key size accepted for RS256 signatures
+const KEY_BITS: usize = 2048;
+
+/// Issuer claim set on minted tokens
+const ISSUER: &str = "sandbox-dev";
+
+/// Default lifetime of minted tokens
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Signs development tokens and publishes the matching JWK set
+pub struct DevIssuer {
+    kid: String,
+    encoding_key: EncodingKey,
+    jwk: Jwk,
+}
+
+#[derive(Debug, Serialize)]
+struct DevClaims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    jti: String,
+    iat: i64,
+    exp: i64,
+    permissions: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gty: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+}
+
+impl DevIssuer {
+    /// Create an issuer from an existing private key
+    pub fn new(key: &RsaPrivateKey) -> Result<Self> {
+        let der = key.to_pkcs1_der()?;
+        let n = URL_SAFE_NO_PAD.encode(key.n().to_bytes_be());
+        let e = URL_SAFE_NO_PAD.encode(key.e().to_bytes_be());
+        let kid = format!("dev-{}", &n[..16]);
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(KeyAlgorithm::RS256),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n,
+                e,
+            }),
+        };
+        Ok(Self {
+            kid,
+            encoding_key: EncodingKey::from_rsa_der(der.as_bytes()),
+            jwk,
+        })
+    }
+
+    /// Load the PKCS#1 DER key at `path`, generating and saving a new key
+    /// if the file doesn't exist yet
+    pub fn load_or_generate(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let key = if path.exists() {
+            let der = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+            RsaPrivateKey::from_pkcs1_der(&der)?
+        } else {
+            let key = RsaPrivateKey::new(&mut rand::thread_rng(), KEY_BITS)?;
+            write_key(path, key.to_pkcs1_der()?.as_bytes())
+                .with_context(|| format!("writing {}", path.display()))?;
+            key
+        };
+        Self::new(&key)
+    }
+
+    /// The JWK set tokens minted by this issuer can be verified with
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: vec![self.jwk.clone()],
+        }
+    }
+
+    /// Mint a signed token for `sub` carrying `permissions`. Subjects ending
+    /// in `@clients` get machine-to-machine claims like Auth0 would issue.
+    pub fn mint(&self, sub: &str, permissions: &[String], ttl: Duration) -> Result<String> {
+        let iat = Utc::now().timestamp();
+        let is_client = sub.ends_with("@clients");
+        let claims = DevClaims {
+            iss: ISSUER,
+            sub,
+            jti: Uuid::now_v7().to_string(),
+            iat,
+            exp: iat + i64::try_from(ttl.as_secs())?,
+            permissions,
+            gty: is_client.then_some("client-credentials"),
+            scope: is_client.then(|| permissions.join(" ")),
+        };
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(self.kid.clone());
+        Ok(encode(&header, &claims, &self.encoding_key)?)
+    }
+}
+
+/// Arguments of the `token` subcommand
+#[derive(Debug, PartialEq, Eq)]
+pub struct TokenArgs {
+    pub sub: String,
+    pub permissions: Vec<String>,
+    pub ttl: Duration,
+}
+
+impl TokenArgs {
+    /// Parse `--sub <sub> [--permissions a,b] [--ttl <seconds>]`
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut sub = None;
+        let mut permissions = Vec::new();
+        let mut ttl = DEFAULT_TTL;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{arg} requires a value"));
+            match arg.as_str() {
+                "--sub" => sub = Some(value()?),
+                "--permissions" => {
+                    permissions = value()?
+                        .split(',')
+                        .filter(|p| !p.is_empty())
+                        .map(ToString::to_string)
+                        .collect();
+                }
+                "--ttl" => ttl = Duration::from_secs(value()?.parse()?),
+                _ => bail!("Unknown argument {arg}"),
+            }
+        }
+        Ok(Self {
+            sub: sub.ok_or_else(|| anyhow!("--sub is required"))?,
+            permissions,
+            ttl,
+        })
+    }
+}
+
+/// Save a key readable by its owner only, anyone who can read it can mint
+/// tokens for any subject
+fn write_key(path: &Path, der: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(der)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth0::Client;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_minted_token_decodes() {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), KEY_BITS).unwrap();
+        let issuer = DevIssuer::new(&key).unwrap();
+        let mut client = Client::new(String::new(), String::new(), String::new());
+        client.add_jwks(issuer.jwks()).unwrap();
+
+        let token = issuer
+            .mint("auth0|123", &args(&["list:user"]), DEFAULT_TTL)
+            .unwrap();
+        let claims = client
+            .decode_token(issuer.kid.clone(), &token)
+            .unwrap()
+            .claims;
+        assert_eq!(claims.sub, "auth0|123");
+        assert_eq!(claims.permissions(), args(&["list:user"]));
+        assert!(!claims.is_client_credentials());
+
+        let token = issuer
+            .mint("abc@clients", &args(&["list:user"]), DEFAULT_TTL)
+            .unwrap();
+        let claims = client
+            .decode_token(issuer.kid.clone(), &token)
+            .unwrap()
+            .claims;
+        assert_eq!(claims.client_id(), Some("abc"));
+        assert_eq!(claims.permissions(), args(&["list:user"]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_key_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("dev-issuer-{}.der", uuid::Uuid::now_v7()));
+        write_key(&path, b"key").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_parse_token_args() {
+        let parsed = TokenArgs::parse(args(&[
+            "--sub",
+            "auth0|123",
+            "--permissions",
+            "list:user,list:account",
+            "--ttl",
+            "60",
+        ]))
+        .unwrap();
+        assert_eq!(
+            parsed,
+            TokenArgs {
+                sub: String::from("auth0|123"),
+                permissions: args(&["list:user", "list:account"]),
+                ttl: Duration::from_secs(60),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_token_args_defaults() {
+        let parsed = TokenArgs::parse(args(&["--sub", "auth0|123"])).unwrap();
+        assert!(parsed.permissions.is_empty());
+        assert_eq!(parsed.ttl, DEFAULT_TTL);
+    }
+
+    #[test]
+    fn test_parse_token_args_errors() {
+        assert!(TokenArgs::parse(args(&[])).is_err());
+        assert!(TokenArgs::parse(args(&["--sub"])).is_err());
+        assert!(TokenArgs::parse(args(&["--sub", "a", "--bogus"])).is_err());
+    }
+}
@@ -25,7 +25,24 @@ pub struct Model {
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-pub enum Relation {}
+pub enum Relation {
+    #[sea_orm(has_many = "super::subscriptions::Entity")]
+    Subscriptions,
+    #[sea_orm(has_many = "super::users_accounts::Entity")]
+    UsersAccounts,
+}
+
+impl Related<super::subscriptions::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Subscriptions.def()
+    }
+}
+
+impl Related<super::users_accounts::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::UsersAccounts.def()
+    }
+}
 
 impl Related<super::users::Entity> for Entity {
     fn to() -> RelationDef {
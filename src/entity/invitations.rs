@@ -0,0 +1,42 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::users_accounts::MembershipRole;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "invitations")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: Uuid,
+    pub account_id: Uuid,
+    #[sea_orm(column_type = "Text")]
+    pub email: String,
+    pub role: MembershipRole,
+    pub invited_by: Option<Uuid>,
+    pub accepted_by: Option<Uuid>,
+    pub expires: DateTimeWithTimeZone,
+    pub accepted: Option<DateTimeWithTimeZone>,
+    pub revoked: Option<DateTimeWithTimeZone>,
+    pub created: DateTimeWithTimeZone,
+    pub updated: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::accounts::Entity",
+        from = "Column::AccountId",
+        to = "super::accounts::Column::Id",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    Accounts,
+}
+
+impl Related<super::accounts::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Accounts.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
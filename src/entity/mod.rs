@@ -1,7 +1,12 @@
 pub mod prelude;
 
 pub mod accounts;
+pub mod exports;
+pub mod invitations;
+pub mod revocations;
+pub mod soft_delete;
 pub mod subscriptions;
 pub mod tasks;
+pub mod tombstones;
 pub mod users;
 pub mod users_accounts;
@@ -1,5 +1,9 @@
 pub use super::accounts::Entity as Accounts;
+pub use super::exports::Entity as Exports;
+pub use super::invitations::Entity as Invitations;
+pub use super::revocations::Entity as Revocations;
 pub use super::subscriptions::Entity as Subscriptions;
 pub use super::tasks::Entity as Tasks;
+pub use super::tombstones::Entity as Tombstones;
 pub use super::users::Entity as Users;
 pub use super::users_accounts::Entity as UsersAccounts;
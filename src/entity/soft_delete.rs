@@ -0,0 +1,90 @@
+use sea_orm::{entity::prelude::*, QueryFilter};
+
+use super::{accounts, revocations, subscriptions, users, users_accounts};
+
+/// Entities that are soft deleted by setting a `deleted` timestamp rather
+/// than removing the row
+pub trait SoftDelete: EntityTrait {
+    /// The nullable timestamp column marking a row as deleted
+    fn deleted_column() -> Self::Column;
+}
+
+/// Scopes queries to rows that haven't been soft deleted. Apply it once per
+/// soft deleted entity in the query, including joined ones.
+pub trait SoftDeleteFilter: QueryFilter + Sized {
+    /// Exclude soft deleted rows of `E`
+    fn active<E: SoftDelete>(self) -> Self {
+        self.filter(E::deleted_column().is_null())
+    }
+
+    /// Exclude soft deleted rows of `E` unless `include_deleted` is set
+    fn scoped<E: SoftDelete>(self, include_deleted: bool) -> Self {
+        if include_deleted {
+            self
+        } else {
+            self.active::<E>()
+        }
+    }
+
+    /// Only keep soft deleted rows of `E`
+    fn deleted<E: SoftDelete>(self) -> Self {
+        self.filter(E::deleted_column().is_not_null())
+    }
+}
+
+impl<Q: QueryFilter> SoftDeleteFilter for Q {}
+
+macro_rules! soft_delete {
+    ($($module:ident),*) => {
+        $(
+            impl SoftDelete for $module::Entity {
+                fn deleted_column() -> Self::Column {
+                    $module::Column::Deleted
+                }
+            }
+        )*
+    };
+}
+
+soft_delete!(accounts, revocations, subscriptions, users, users_accounts);
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{DbBackend, QueryTrait};
+
+    use super::*;
+    use crate::entity::prelude::*;
+
+    #[test]
+    fn test_scoped_excludes_deleted() {
+        let sql = Users::find()
+            .scoped::<Users>(false)
+            .build(DbBackend::Postgres)
+            .to_string();
+        assert!(sql.ends_with(r#"WHERE "users"."deleted" IS NULL"#), "{sql}");
+    }
+
+    #[test]
+    fn test_scoped_include_deleted() {
+        let sql = Users::find()
+            .scoped::<Users>(true)
+            .build(DbBackend::Postgres)
+            .to_string();
+        assert!(!sql.contains("WHERE"), "{sql}");
+    }
+
+    #[test]
+    fn test_active_joined_entities() {
+        let sql = Users::find()
+            .find_with_related(Accounts)
+            .active::<Accounts>()
+            .active::<UsersAccounts>()
+            .build(DbBackend::Postgres)
+            .to_string();
+        assert!(sql.contains(r#""accounts"."deleted" IS NULL"#), "{sql}");
+        assert!(
+            sql.contains(r#""users_accounts"."deleted" IS NULL"#),
+            "{sql}"
+        );
+    }
+}
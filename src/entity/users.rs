@@ -10,13 +10,31 @@ pub struct Model {
     pub provider_id: String,
     #[sea_orm(column_type = "Text")]
     pub stripe_customer_id: String,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub name: Option<String>,
+    /// Set when the name was given through the API rather than by the provider
+    pub name_overridden: bool,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub email: Option<String>,
+    pub email_verified: bool,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub avatar_url: Option<String>,
     pub created: DateTimeWithTimeZone,
     pub updated: DateTimeWithTimeZone,
     pub deleted: Option<DateTimeWithTimeZone>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-pub enum Relation {}
+pub enum Relation {
+    #[sea_orm(has_many = "super::users_accounts::Entity")]
+    UsersAccounts,
+}
+
+impl Related<super::users_accounts::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::UsersAccounts.def()
+    }
+}
 
 impl Related<super::accounts::Entity> for Entity {
     fn to() -> RelationDef {
@@ -28,3 +46,24 @@ impl Related<super::accounts::Entity> for Entity {
 }
 
 impl ActiveModelBehavior for ActiveModel {}
+
+#[cfg(test)]
+impl Model {
+    /// An active user with no profile, for tests to fill in what they need
+    pub fn for_tests(provider_id: &str) -> Self {
+        let now = chrono::Utc::now().into();
+        Self {
+            id: Uuid::now_v7(),
+            provider_id: provider_id.to_string(),
+            stripe_customer_id: String::from("placeholder"),
+            name: None,
+            name_overridden: false,
+            email: None,
+            email_verified: false,
+            avatar_url: None,
+            created: now,
+            updated: now,
+            deleted: None,
+        }
+    }
+}
@@ -1,12 +1,37 @@
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, EnumIter, DeriveActiveEnum)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+pub enum MembershipRole {
+    Member = 0,
+    Admin = 1,
+    Owner = 2,
+    Billing = 3,
+}
+
+impl MembershipRole {
+    /// Whether this role grants at least the access of `required`.
+    /// Owners can do anything, admins can do anything a member can,
+    /// and billing members can see the account along with its billing.
+    pub fn satisfies(self, required: MembershipRole) -> bool {
+        match self {
+            Self::Owner => true,
+            Self::Admin => matches!(required, Self::Admin | Self::Member),
+            Self::Billing => matches!(required, Self::Billing | Self::Member),
+            Self::Member => required == Self::Member,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
 #[sea_orm(table_name = "users_accounts")]
 pub struct Model {
-    #[sea_orm(primary_key)]
+    #[sea_orm(primary_key, auto_increment = false)]
     pub user_id: Uuid,
+    #[sea_orm(primary_key, auto_increment = false)]
     pub account_id: Uuid,
+    pub role: MembershipRole,
     pub created: DateTimeWithTimeZone,
     pub updated: DateTimeWithTimeZone,
     pub deleted: Option<DateTimeWithTimeZone>,
@@ -32,4 +57,16 @@ pub enum Relation {
     Users,
 }
 
+impl Related<super::accounts::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Accounts.def()
+    }
+}
+
+impl Related<super::users::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Users.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
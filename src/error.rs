@@ -1,7 +1,9 @@
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
+use serde_json::json;
 use thiserror::Error;
 use tracing::error;
 
@@ -22,6 +24,12 @@ pub enum Error {
     #[error("Not Found")]
     NotFound,
 
+    #[error("Bad Request: {0}")]
+    BadRequest(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
     #[error("Too Many Requests")]
     TooManyRequests,
 
@@ -67,6 +75,12 @@ impl IntoResponse for Error {
             Self::Unauthorized => StatusCode::UNAUTHORIZED.into_response(),
             Self::Forbidden => StatusCode::FORBIDDEN.into_response(),
             Self::NotFound => StatusCode::NOT_FOUND.into_response(),
+            Self::BadRequest(message) => {
+                (StatusCode::BAD_REQUEST, Json(json!({ "error": message }))).into_response()
+            }
+            Self::Conflict(message) => {
+                (StatusCode::CONFLICT, Json(json!({ "error": message }))).into_response()
+            }
             Self::TooManyRequests => StatusCode::TOO_MANY_REQUESTS.into_response(),
             Self::Auth0 => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             Self::WebhookError(e) => {
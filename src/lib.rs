@@ -10,8 +10,23 @@ pub mod entity;
 /// Export Auth0 client
 pub mod auth0;
 
+/// Export development token issuer
+pub mod dev_issuer;
+
 /// Export error type
 pub mod error;
 
 /// Export token bucket
 pub mod token_bucket;
+
+/// Export rate limit stores
+pub mod rate_limit;
+
+/// Export token revocation list
+pub mod revocation;
+
+/// Export signed token helpers
+pub mod signed_token;
+
+/// Export authenticated user cache
+pub mod user_cache;
@@ -2,12 +2,13 @@
 #![deny(clippy::pedantic)]
 #![forbid(unsafe_code)]
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use dotenvy::dotenv;
 use figment::providers::{Env, Serialized};
 use figment::Figment;
 use sandbox_api::api;
 use sandbox_api::config::Config;
+use sandbox_api::dev_issuer::{DevIssuer, TokenArgs};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{fmt, EnvFilter};
 
@@ -24,7 +25,17 @@ async fn main() -> Result<()> {
         .merge(Env::raw())
         .extract()?;
 
-    api::serve(config).await?;
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None | Some("serve") => api::serve(config).await?,
+        // Mint a token from the local development issuer
+        Some("token") => {
+            let args = TokenArgs::parse(args)?;
+            let issuer = DevIssuer::load_or_generate(&config.dev_issuer_key_path)?;
+            println!("{}", issuer.mint(&args.sub, &args.permissions, args.ttl)?);
+        }
+        Some(command) => bail!("Unknown command {command}"),
+    }
 
     Ok(())
 }
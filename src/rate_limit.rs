@@ -0,0 +1,373 @@
+use std::time::{Duration, Instant};
+
+use axum::async_trait;
+use dashmap::DashMap;
+use sea_orm::{ConnectionTrait, DatabaseConnection, Statement};
+
+use crate::{
+    error::Error,
+    token_bucket::{Rate, TokenBucket},
+};
+
+/// Reserves a batch of tokens from a shared bucket, refilling it for the time
+/// since it was last touched. Buckets are created full. The grant is stored
+/// alongside the bucket so it can be returned, `RETURNING` only sees the new
+/// row.
+const RESERVE: &str = r#"
+INSERT INTO rate_limits AS bucket (key, tokens, granted, updated, refilled)
+VALUES (
+    $1,
+    $2 - LEAST($4, $2),
+    LEAST($4, $2)::INT,
+    NOW(),
+    NOW() + make_interval(secs => LEAST($4, $2) / NULLIF($3, 0))
+)
+ON CONFLICT (key) DO UPDATE SET
+    tokens = {remaining},
+    granted = LEAST($4, FLOOR({available}))::INT,
+    updated = NOW(),
+    refilled = NOW() + make_interval(secs => ($2 - ({remaining})) / NULLIF($3, 0))
+RETURNING granted, tokens
+"#;
+
+/// Tokens left in a stored bucket after the grant
+const REMAINING: &str = "{available} - LEAST($4, FLOOR({available}))";
+
+/// Tokens in a stored bucket after refilling, capped at capacity
+const AVAILABLE: &str =
+    "LEAST($2, bucket.tokens + EXTRACT(EPOCH FROM NOW() - bucket.updated) * $3)";
+
+/// Removes buckets that have refilled, they're recreated full
+const PURGE: &str = "DELETE FROM rate_limits WHERE refilled < NOW()";
+
+/// The size of a bucket and how quickly it refills
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub capacity: u32,
+    pub fill_rate: Rate,
+    /// Tokens taken per request
+    pub take_rate: u32,
+}
+
+/// The outcome of taking from a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: Limit,
+    /// Tokens left in the bucket
+    pub remaining: u32,
+    /// Time until the bucket is full again
+    pub reset: Duration,
+    /// Time until a request would be allowed, only set when it wasn't
+    pub retry_after: Option<Duration>,
+}
+
+impl Decision {
+    /// A decision for a bucket holding a fractional number of tokens
+    fn new(allowed: bool, limit: Limit, tokens: f64) -> Self {
+        let time_until = |target: u32| {
+            let seconds =
+                (f64::from(target) - tokens).max(0.0) / limit.fill_rate.tokens_per_second();
+            if seconds.is_nan() {
+                Duration::ZERO
+            } else {
+                Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX)
+            }
+        };
+        Self {
+            allowed,
+            limit,
+            remaining: tokens.max(0.0) as u32,
+            reset: time_until(limit.capacity),
+            retry_after: (!allowed).then(|| time_until(limit.take_rate)),
+        }
+    }
+}
+
+/// Where rate limit buckets are kept
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Take a request's worth of tokens from the bucket for `key`. A key is
+    /// always used with the same limit.
+    async fn take(&self, key: &str, limit: Limit) -> Result<Decision, Error>;
+
+    /// Drop buckets that are no longer needed
+    async fn purge(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Buckets held in memory, limits apply to each replica separately
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    buckets: DashMap<String, TokenBucket>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn take(&self, key: &str, limit: Limit) -> Result<Decision, Error> {
+        let mut bucket = self
+            .buckets
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket::new(limit.capacity, limit.fill_rate, limit.take_rate));
+        let allowed = bucket.take();
+        Ok(Decision {
+            allowed,
+            limit,
+            remaining: bucket.remaining(),
+            reset: bucket.time_until(limit.capacity),
+            retry_after: (!allowed).then(|| bucket.time_until(limit.take_rate)),
+        })
+    }
+}
+
+/// Tokens a replica has reserved from a shared bucket but not yet used
+#[derive(Debug, Clone, Copy)]
+struct Allowance {
+    tokens: u32,
+    /// Tokens left in the shared bucket as of the last reservation
+    shared: f64,
+    expires: Instant,
+    /// Set when the last reservation came back short, requests are refused
+    /// locally until then rather than reserving again
+    denied_until: Option<Instant>,
+}
+
+impl Allowance {
+    /// Take `amount` tokens, false if the allowance is short or has expired.
+    /// An expired allowance is emptied.
+    fn take(&mut self, amount: u32, now: Instant) -> bool {
+        if now >= self.expires {
+            self.tokens = 0;
+        }
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The refusal still in force at `now`, if any. The shared bucket holds
+    /// a request's worth of tokens again when it lifts, so the tokens it holds
+    /// now are counted back from there.
+    fn denial(&self, limit: Limit, now: Instant) -> Option<Decision> {
+        let wait = self
+            .denied_until?
+            .checked_duration_since(now)
+            .filter(|wait| !wait.is_zero())?;
+        let tokens =
+            f64::from(limit.take_rate) - wait.as_secs_f64() * limit.fill_rate.tokens_per_second();
+        Some(Decision::new(false, limit, tokens))
+    }
+}
+
+/// Buckets shared by every replica through Postgres. To avoid a round trip
+/// per request, tokens are reserved in batches and handed out locally until
+/// they run out. Reserved tokens expire after `lease` so a replica can't sit
+/// on them, at worst a client loses a batch per replica, it never gains one.
+#[derive(Debug)]
+pub struct PostgresStore {
+    db: DatabaseConnection,
+    batch: u32,
+    lease: Duration,
+    allowances: DashMap<String, Allowance>,
+}
+
+impl PostgresStore {
+    pub fn new(db: DatabaseConnection, batch: u32, lease: Duration) -> Self {
+        Self {
+            db,
+            batch,
+            lease,
+            allowances: DashMap::new(),
+        }
+    }
+
+    /// Reserve up to `amount` tokens from the shared bucket, returning the
+    /// tokens granted and those left behind
+    async fn reserve(&self, key: &str, limit: Limit, amount: u32) -> Result<(u32, f64), Error> {
+        let backend = self.db.get_database_backend();
+        let statement = Statement::from_sql_and_values(
+            backend,
+            RESERVE
+                .replace("{remaining}", REMAINING)
+                .replace("{available}", AVAILABLE),
+            [
+                key.into(),
+                f64::from(limit.capacity).into(),
+                limit.fill_rate.tokens_per_second().into(),
+                f64::from(amount).into(),
+            ],
+        );
+        let Some(row) = self.db.query_one(statement).await? else {
+            return Ok((0, 0.0));
+        };
+        let granted = row.try_get::<i32>("", "granted")?;
+        let tokens = row.try_get::<f64>("", "tokens")?;
+        Ok((granted.max(0) as u32, tokens))
+    }
+
+    /// Answer from the replica's allowance or a refusal still in force,
+    /// `None` when tokens need reserving
+    fn take_local(&self, key: &str, limit: Limit, now: Instant) -> Option<Decision> {
+        let mut allowance = self.allowances.get_mut(key)?;
+        if let Some(decision) = allowance.denial(limit, now) {
+            return Some(decision);
+        }
+        if !allowance.take(limit.take_rate, now) {
+            return None;
+        }
+        let tokens = f64::from(allowance.tokens) + allowance.shared;
+        Some(Decision::new(true, limit, tokens))
+    }
+
+    /// Add a reservation to the replica's allowance and take from it
+    fn grant(&self, key: &str, limit: Limit, granted: u32, shared: f64, now: Instant) -> Decision {
+        let mut allowance = self.allowances.entry(key.to_string()).or_insert(Allowance {
+            tokens: 0,
+            shared,
+            expires: now,
+            denied_until: None,
+        });
+        if now >= allowance.expires {
+            allowance.tokens = 0;
+        }
+        allowance.tokens += granted;
+        allowance.shared = shared;
+        allowance.expires = now + self.lease;
+        let allowed = allowance.take(limit.take_rate, now);
+        let tokens = f64::from(allowance.tokens) + allowance.shared;
+        let decision = Decision::new(allowed, limit, tokens);
+        allowance.denied_until = decision
+            .retry_after
+            .and_then(|retry_after| now.checked_add(retry_after));
+        decision
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for PostgresStore {
+    /// Remaining tokens are estimated from the replica's allowance and the
+    /// shared bucket as of the last reservation, other replicas may have
+    /// taken from it since
+    async fn take(&self, key: &str, limit: Limit) -> Result<Decision, Error> {
+        let now = Instant::now();
+        if let Some(decision) = self.take_local(key, limit, now) {
+            return Ok(decision);
+        }
+
+        // The lock isn't held while reserving, concurrent requests that run
+        // short each reserve their own batch
+        let (granted, shared) = self
+            .reserve(key, limit, self.batch.max(limit.take_rate))
+            .await?;
+        Ok(self.grant(key, limit, granted, shared, now))
+    }
+
+    async fn purge(&self) -> Result<(), Error> {
+        let backend = self.db.get_database_backend();
+        self.db
+            .execute(Statement::from_string(backend, PURGE))
+            .await?;
+        let now = Instant::now();
+        self.allowances.retain(|_, allowance| {
+            allowance.expires > now || allowance.denied_until.is_some_and(|until| until > now)
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_store_keys() {
+        let store = MemoryStore::new();
+        let limit = Limit {
+            capacity: 1,
+            fill_rate: Rate::per_second(1),
+            take_rate: 1,
+        };
+        assert!(store.take("ip:1.1.1.1", limit).await.unwrap().allowed);
+        let decision = store.take("ip:1.1.1.1", limit).await.unwrap();
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert!(decision.retry_after.is_some());
+        assert!(store.take("ip:2.2.2.2", limit).await.unwrap().allowed);
+    }
+
+    #[test]
+    fn test_decision() {
+        let limit = Limit {
+            capacity: 10,
+            fill_rate: Rate::per_second(2),
+            take_rate: 1,
+        };
+        let decision = Decision::new(true, limit, 4.5);
+        assert_eq!(decision.remaining, 4);
+        assert_eq!(decision.reset, Duration::from_millis(2750));
+        assert_eq!(decision.retry_after, None);
+        let decision = Decision::new(false, limit, 0.5);
+        assert_eq!(decision.retry_after, Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn test_allowance() {
+        let now = Instant::now();
+        let mut allowance = Allowance {
+            tokens: 2,
+            shared: 0.0,
+            expires: now + Duration::from_secs(1),
+            denied_until: None,
+        };
+        assert!(allowance.take(1, now));
+        assert!(!allowance.take(2, now));
+        assert!(allowance.take(1, now));
+        assert!(!allowance.take(1, now));
+    }
+
+    #[test]
+    fn test_allowance_expires() {
+        let now = Instant::now();
+        let mut allowance = Allowance {
+            tokens: 5,
+            shared: 0.0,
+            expires: now,
+            denied_until: None,
+        };
+        assert!(!allowance.take(1, now));
+        assert_eq!(allowance.tokens, 0);
+    }
+
+    #[tokio::test]
+    async fn test_postgres_store_caches_denials() {
+        // The connection is never opened, reserving would panic
+        let store = PostgresStore::new(DatabaseConnection::default(), 10, Duration::from_secs(5));
+        let limit = Limit {
+            capacity: 10,
+            fill_rate: Rate::per_second(1),
+            take_rate: 1,
+        };
+        let now = Instant::now();
+        let denied = store.grant("ip:1.1.1.1", limit, 0, 0.5, now);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(500)));
+
+        let decision = store.take("ip:1.1.1.1", limit).await.unwrap();
+        assert!(!decision.allowed);
+        assert!(decision.retry_after.unwrap() <= Duration::from_millis(500));
+        let later = now + Duration::from_millis(400);
+        let decision = store.take_local("ip:1.1.1.1", limit, later).unwrap();
+        assert!(decision.retry_after.unwrap() <= Duration::from_millis(100));
+        let lifted = now + Duration::from_millis(500);
+        assert!(store.take_local("ip:1.1.1.1", limit, lifted).is_none());
+    }
+}
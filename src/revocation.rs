@@ -0,0 +1,184 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use chrono::{DateTime, Utc};
+
+use crate::auth0::AuthClaims;
+
+/// A single entry in the revocation list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Revocation {
+    /// Deny the token with this `jti`
+    Token(String),
+    /// Deny every token issued to this subject
+    Subject(String),
+    /// Deny tokens issued to this subject before the given time
+    IssuedBefore(String, DateTime<Utc>),
+}
+
+#[derive(Debug, Default)]
+struct Revocations {
+    tokens: HashSet<String>,
+    /// `None` blocks the subject entirely, otherwise tokens issued
+    /// before the time are denied
+    subjects: HashMap<String, Option<DateTime<Utc>>>,
+}
+
+impl Revocations {
+    fn insert(&mut self, revocation: Revocation) {
+        match revocation {
+            Revocation::Token(jti) => {
+                self.tokens.insert(jti);
+            }
+            Revocation::Subject(subject) => {
+                self.subjects.insert(subject, None);
+            }
+            Revocation::IssuedBefore(subject, before) => {
+                let entry = self.subjects.entry(subject).or_insert(Some(before));
+                if let Some(current) = entry {
+                    *current = (*current).max(before);
+                }
+            }
+        }
+    }
+}
+
+/// In-memory copy of the revocation list consulted on every authenticated
+/// request. Postgres is the source of truth, the list is periodically
+/// replaced with the stored entries so revocations made on other replicas
+/// are picked up.
+///
+/// Entries are keyed on the token subject, so blocking a subject denies
+/// every token issued to it, not just a single session. Machine-to-machine
+/// applications have subjects of their own and aren't tied to a user, so
+/// blocking a user leaves them untouched.
+#[derive(Debug, Default)]
+pub struct RevocationList {
+    inner: RwLock<Revocations>,
+}
+
+impl RevocationList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the list with a fresh set of entries
+    pub fn replace(&self, entries: impl IntoIterator<Item = Revocation>) {
+        let mut revocations = Revocations::default();
+        for entry in entries {
+            revocations.insert(entry);
+        }
+        *self.inner.write().expect("revocation list lock poisoned") = revocations;
+    }
+
+    /// Add a single entry without waiting for the next refresh
+    pub fn insert(&self, revocation: Revocation) {
+        self.inner
+            .write()
+            .expect("revocation list lock poisoned")
+            .insert(revocation);
+    }
+
+    /// Whether a token with the given claims has been revoked. Tokens
+    /// without an `iat` claim are denied when their subject has an
+    /// issued-before revocation, since their age can't be proven.
+    pub fn is_revoked(&self, claims: &AuthClaims) -> bool {
+        let revocations = self.inner.read().expect("revocation list lock poisoned");
+        if let Some(jti) = &claims.jti {
+            if revocations.tokens.contains(jti) {
+                return true;
+            }
+        }
+        match revocations.subjects.get(&claims.sub) {
+            None => false,
+            Some(None) => true,
+            Some(Some(before)) => claims
+                .iat
+                .and_then(|iat| DateTime::from_timestamp(iat, 0))
+                .is_none_or(|issued| issued < *before),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(sub: &str, jti: Option<&str>, iat: Option<i64>) -> AuthClaims {
+        AuthClaims {
+            sub: sub.to_string(),
+            jti: jti.map(ToString::to_string),
+            iat,
+            exp: None,
+            permissions: None,
+            scope: None,
+            gty: None,
+        }
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    #[test]
+    fn test_revoke_token() {
+        let list = RevocationList::new();
+        list.insert(Revocation::Token(String::from("abc")));
+        assert!(list.is_revoked(&claims("auth0|1", Some("abc"), None)));
+        assert!(!list.is_revoked(&claims("auth0|1", Some("def"), None)));
+        assert!(!list.is_revoked(&claims("auth0|1", None, None)));
+    }
+
+    #[test]
+    fn test_revoke_subject() {
+        let list = RevocationList::new();
+        list.insert(Revocation::Subject(String::from("auth0|1")));
+        assert!(list.is_revoked(&claims("auth0|1", None, Some(100))));
+        assert!(!list.is_revoked(&claims("auth0|2", None, Some(100))));
+    }
+
+    #[test]
+    fn test_revoke_subject_leaves_services() {
+        let list = RevocationList::new();
+        list.insert(Revocation::Subject(String::from("auth0|1")));
+        let mut service = claims("client@clients", None, Some(100));
+        service.gty = Some(String::from("client-credentials"));
+        assert!(!list.is_revoked(&service));
+        list.insert(Revocation::Subject(String::from("client@clients")));
+        assert!(list.is_revoked(&service));
+    }
+
+    #[test]
+    fn test_revoke_issued_before() {
+        let list = RevocationList::new();
+        list.insert(Revocation::IssuedBefore(String::from("auth0|1"), at(100)));
+        assert!(list.is_revoked(&claims("auth0|1", None, Some(99))));
+        assert!(!list.is_revoked(&claims("auth0|1", None, Some(100))));
+        assert!(list.is_revoked(&claims("auth0|1", None, None)));
+    }
+
+    #[test]
+    fn test_revoke_issued_before_keeps_latest() {
+        let list = RevocationList::new();
+        list.insert(Revocation::IssuedBefore(String::from("auth0|1"), at(200)));
+        list.insert(Revocation::IssuedBefore(String::from("auth0|1"), at(100)));
+        assert!(list.is_revoked(&claims("auth0|1", None, Some(150))));
+    }
+
+    #[test]
+    fn test_subject_block_wins() {
+        let list = RevocationList::new();
+        list.insert(Revocation::Subject(String::from("auth0|1")));
+        list.insert(Revocation::IssuedBefore(String::from("auth0|1"), at(100)));
+        assert!(list.is_revoked(&claims("auth0|1", None, Some(200))));
+    }
+
+    #[test]
+    fn test_replace() {
+        let list = RevocationList::new();
+        list.insert(Revocation::Subject(String::from("auth0|1")));
+        list.replace([Revocation::Subject(String::from("auth0|2"))]);
+        assert!(!list.is_revoked(&claims("auth0|1", None, None)));
+        assert!(list.is_revoked(&claims("auth0|2", None, None)));
+    }
+}
@@ -0,0 +1,100 @@
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Signs short lived tokens the API hands out itself, like invitation and
+/// download links. Each token is bound to a purpose through the `aud`
+/// claim, so a token issued for one purpose can't be redeemed for another.
+#[derive(Clone)]
+pub struct TokenSigner {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedClaims {
+    sub: String,
+    aud: String,
+    exp: i64,
+}
+
+impl TokenSigner {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+        }
+    }
+
+    /// Sign `subject` for `purpose`, valid until `expires`
+    pub fn sign(
+        &self,
+        purpose: &str,
+        subject: &str,
+        expires: DateTime<Utc>,
+    ) -> Result<String, Error> {
+        let claims = SignedClaims {
+            sub: subject.to_string(),
+            aud: purpose.to_string(),
+            exp: expires.timestamp(),
+        };
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)
+            .map_err(|e| Error::Anyhow(e.into()))
+    }
+
+    /// Verify a token signed for `purpose` and return its subject
+    pub fn verify(&self, purpose: &str, token: &str) -> Result<String, Error> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_audience(&[purpose]);
+        validation.set_required_spec_claims(&["exp", "aud", "sub"]);
+        validation.leeway = 0;
+        let claims = decode::<SignedClaims>(token, &self.decoding_key, &validation)
+            .map_err(|_| Error::NotFound)?
+            .claims;
+        Ok(claims.sub)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = TokenSigner::new(b"secret");
+        let expires = Utc::now() + Duration::minutes(5);
+        let token = signer.sign("invitation", "abc", expires).unwrap();
+        assert_eq!(signer.verify("invitation", &token).unwrap(), "abc");
+    }
+
+    #[test]
+    fn test_wrong_purpose() {
+        let signer = TokenSigner::new(b"secret");
+        let expires = Utc::now() + Duration::minutes(5);
+        let token = signer.sign("invitation", "abc", expires).unwrap();
+        assert!(signer.verify("download", &token).is_err());
+    }
+
+    #[test]
+    fn test_wrong_secret() {
+        let expires = Utc::now() + Duration::minutes(5);
+        let token = TokenSigner::new(b"secret")
+            .sign("invitation", "abc", expires)
+            .unwrap();
+        assert!(TokenSigner::new(b"other")
+            .verify("invitation", &token)
+            .is_err());
+    }
+
+    #[test]
+    fn test_expired() {
+        let signer = TokenSigner::new(b"secret");
+        let expires = Utc::now() - Duration::minutes(5);
+        let token = signer.sign("invitation", "abc", expires).unwrap();
+        assert!(signer.verify("invitation", &token).is_err());
+    }
+}
@@ -1,101 +1,199 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+/// Tokens added to a bucket over a period, e.g. 5 per minute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub amount: u32,
+    pub per: Duration,
+}
+
+impl Rate {
+    pub fn new(amount: u32, per: Duration) -> Self {
+        Self { amount, per }
+    }
+
+    pub fn per_second(amount: u32) -> Self {
+        Self::new(amount, Duration::from_secs(1))
+    }
+
+    /// Tokens added each second, zero for an empty period
+    pub fn tokens_per_second(&self) -> f64 {
+        if self.per.is_zero() {
+            0.0
+        } else {
+            f64::from(self.amount) / self.per.as_secs_f64()
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct TokenBucket {
-    capacity: u8,
-    available_tokens: u8,
+    capacity: u32,
+    // Tokens are tracked fractionally so slow rates refill smoothly
+    available_tokens: f64,
     last_update: Instant,
-    fill_rate: u8,
-    take_rate: u8,
+    fill_rate: f64,
+    take_rate: u32,
 }
 
 impl Default for TokenBucket {
     fn default() -> Self {
-        Self::new(1, 1, 1)
+        Self::new(1, Rate::per_second(1), 1)
     }
 }
 
 impl TokenBucket {
-    pub fn new(capacity: u8, fill_rate: u8, take_rate: u8) -> Self {
+    pub fn new(capacity: u32, fill_rate: Rate, take_rate: u32) -> Self {
         Self {
             capacity,
-            available_tokens: capacity,
+            available_tokens: f64::from(capacity),
             last_update: Instant::now(),
-            fill_rate,
+            fill_rate: fill_rate.tokens_per_second(),
             take_rate,
         }
     }
 
+    /// Tokens held at `now`, counting those added since the last update
+    fn tokens_at(&self, now: Instant) -> f64 {
+        // A last update in the future adds nothing rather than underflowing
+        let elapsed = now.saturating_duration_since(self.last_update);
+        // Floating point can't overflow here, however long the bucket sat
+        // idle it's clamped back to capacity
+        (self.available_tokens + elapsed.as_secs_f64() * self.fill_rate)
+            .min(f64::from(self.capacity))
+    }
+
     pub fn take(&mut self) -> bool {
         let now = Instant::now();
-        let elapsed = now.duration_since(self.last_update).as_secs();
-        // Max capacity is u8 so we need to clamp elapsed time
-        let elapsed = elapsed.min(u8::MAX as u64) as u8;
-        let tokens_to_add = elapsed.saturating_mul(self.fill_rate);
-        // Check if we have at least one token to add
-        // to prevent fractional token tracking
-        if tokens_to_add >= 1 {
-            self.available_tokens = self
-                .available_tokens
-                .saturating_add(tokens_to_add)
-                .min(self.capacity);
-            self.last_update = now;
-        }
-        if self.available_tokens >= self.take_rate {
-            self.available_tokens -= self.take_rate;
+        self.available_tokens = self.tokens_at(now);
+        self.last_update = now;
+        let take_rate = f64::from(self.take_rate);
+        if self.available_tokens >= take_rate {
+            self.available_tokens -= take_rate;
             true
         } else {
             false
         }
     }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Whole tokens currently left
+    pub fn remaining(&self) -> u32 {
+        self.tokens_at(Instant::now()) as u32
+    }
+
+    /// Time until another whole token is available, zero when the bucket is
+    /// full
+    pub fn time_to_next_token(&self) -> Duration {
+        self.time_until(self.remaining().saturating_add(1))
+    }
+
+    /// Time until the bucket holds at least `tokens`, capped at capacity
+    pub fn time_until(&self, tokens: u32) -> Duration {
+        let needed = f64::from(tokens.min(self.capacity)) - self.tokens_at(Instant::now());
+        if needed <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::try_from_secs_f64(needed / self.fill_rate).unwrap_or(Duration::MAX)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
-
     use super::*;
 
     #[test]
     fn test_token_bucket() {
-        let mut bucket = TokenBucket::new(1, 1, 1);
+        let mut bucket = TokenBucket::new(1, Rate::per_second(1), 1);
         assert!(bucket.take());
         assert!(!bucket.take());
     }
 
     #[test]
     fn test_token_bucket_fill_rate() {
-        let mut bucket = TokenBucket::new(1, 1, 1);
+        let mut bucket = TokenBucket::new(1, Rate::per_second(1), 1);
         assert!(bucket.take());
         std::thread::sleep(std::time::Duration::from_secs(1));
         assert!(bucket.take());
         assert!(!bucket.take());
     }
 
+    #[test]
+    fn test_token_bucket_sub_second_fill() {
+        let mut bucket = TokenBucket::new(1, Rate::per_second(20), 1);
+        assert!(bucket.take());
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        assert!(bucket.take());
+    }
+
+    #[test]
+    fn test_token_bucket_slow_rate() {
+        let mut bucket = TokenBucket::new(5, Rate::new(5, Duration::from_secs(60)), 1);
+        for _ in 0..5 {
+            assert!(bucket.take());
+        }
+        assert!(!bucket.take());
+        // A token is added every 12 seconds
+        bucket.last_update -= Duration::from_secs(13);
+        assert!(bucket.take());
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn test_token_bucket_wide_capacity() {
+        let mut bucket = TokenBucket::new(100_000, Rate::per_second(1), 1);
+        assert!(bucket.take());
+        assert_eq!(bucket.remaining(), 99_999);
+    }
+
+    #[test]
+    fn test_token_bucket_time_until() {
+        let mut bucket = TokenBucket::new(10, Rate::per_second(2), 1);
+        assert_eq!(bucket.time_to_next_token(), Duration::ZERO);
+        bucket.available_tokens = 5.0;
+        bucket.last_update = Instant::now();
+        assert_eq!(bucket.remaining(), 5);
+        let next = bucket.time_to_next_token();
+        assert!(next > Duration::ZERO && next <= Duration::from_millis(500));
+        let full = bucket.time_until(bucket.capacity());
+        assert!(full > Duration::from_secs(2) && full <= Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn test_token_bucket_never_fills() {
+        let mut bucket = TokenBucket::new(1, Rate::new(1, Duration::ZERO), 1);
+        assert!(bucket.take());
+        assert_eq!(bucket.time_to_next_token(), Duration::MAX);
+    }
+
     #[test]
     fn test_token_bucket_fill_rate_overflow() {
-        let mut bucket = TokenBucket::new(255, 255, 1);
+        let mut bucket = TokenBucket::new(255, Rate::per_second(255), 1);
         std::thread::sleep(std::time::Duration::from_secs(1));
         assert!(bucket.take());
-        assert_eq!(bucket.available_tokens, 254);
+        assert_eq!(bucket.available_tokens, 254.0);
     }
 
     #[test]
     fn test_token_bucket_overflow() {
         const PAST: u64 = 1_000_000_000;
-        let mut bucket = TokenBucket::new(255, 255, 1);
-        // Check that we can't overflow tokens_to_add
+        let mut bucket = TokenBucket::new(u32::MAX, Rate::per_second(u32::MAX), 1);
+        // Check that we can't overflow the tokens added
         bucket.last_update = Instant::now() - Duration::from_secs(PAST);
         assert!(bucket.take());
+        assert_eq!(bucket.available_tokens, f64::from(u32::MAX - 1));
     }
 
     #[test]
     fn test_token_bucket_underflow() {
         const FUTURE: u64 = 1_000_000_000;
-        let mut bucket = TokenBucket::new(255, 1, 1);
-        // Check that we can't underflow tokens_to_add
+        let mut bucket = TokenBucket::new(255, Rate::per_second(1), 1);
+        // Check that we can't underflow the time elapsed
         bucket.last_update = Instant::now() + Duration::from_secs(FUTURE);
         assert!(bucket.take());
-        assert_eq!(bucket.available_tokens, 254);
+        assert_eq!(bucket.available_tokens, 254.0);
     }
 }
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::entity::users;
+
+/// Most users kept before expired entries are swept
+const CAPACITY: usize = 10_000;
+
+/// Short lived cache of users keyed by provider id, sparing authenticated
+/// requests a database lookup. Changes made on this replica invalidate the
+/// entry, changes made elsewhere are picked up once it expires.
+#[derive(Debug)]
+pub struct UserCache {
+    ttl: Duration,
+    entries: DashMap<String, (Instant, users::Model)>,
+}
+
+impl UserCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: DashMap::new(),
+        }
+    }
+
+    /// The cached user for a provider id, if it hasn't expired
+    pub fn get(&self, provider_id: &str) -> Option<users::Model> {
+        let entry = self.entries.get(provider_id)?;
+        let (cached, user) = entry.value();
+        if cached.elapsed() < self.ttl {
+            return Some(user.clone());
+        }
+        drop(entry);
+        self.entries
+            .remove_if(provider_id, |_, (cached, _)| cached.elapsed() >= self.ttl);
+        None
+    }
+
+    pub fn insert(&self, user: users::Model) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        if self.entries.len() >= CAPACITY {
+            self.entries
+                .retain(|_, (cached, _)| cached.elapsed() < self.ttl);
+        }
+        if self.entries.len() >= CAPACITY {
+            self.entries.clear();
+        }
+        self.entries
+            .insert(user.provider_id.clone(), (Instant::now(), user));
+    }
+
+    /// Drop a user from the cache after it has changed
+    pub fn invalidate(&self, provider_id: &str) {
+        self.entries.remove(provider_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_cached() {
+        let cache = UserCache::new(Duration::from_secs(60));
+        let cached = users::Model::for_tests("auth0|1");
+        cache.insert(cached.clone());
+        assert_eq!(cache.get("auth0|1"), Some(cached));
+        assert_eq!(cache.get("auth0|2"), None);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cache = UserCache::new(Duration::from_secs(60));
+        cache.insert(users::Model::for_tests("auth0|1"));
+        cache.invalidate("auth0|1");
+        assert_eq!(cache.get("auth0|1"), None);
+    }
+
+    #[test]
+    fn test_expired() {
+        let cache = UserCache::new(Duration::from_millis(1));
+        cache.insert(users::Model::for_tests("auth0|1"));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(cache.get("auth0|1"), None);
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let cache = UserCache::new(Duration::ZERO);
+        cache.insert(users::Model::for_tests("auth0|1"));
+        assert_eq!(cache.get("auth0|1"), None);
+    }
+}